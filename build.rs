@@ -0,0 +1,37 @@
+// Embed the git hash and build time for GET /version
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    if let Ok(output) = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+    {
+        if output.status.success() {
+            let hash = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=VIBEKEYS_GIT_HASH={}", hash.trim());
+        }
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    println!("cargo:rustc-env=VIBEKEYS_BUILD_TIMESTAMP={}", timestamp);
+
+    // HEAD only changes when switching branches; a new commit moves the ref
+    // it points to, which may live loose or in packed-refs. The timestamp is
+    // refreshed along with the hash.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    let mut watched = vec![".git/packed-refs".to_string()];
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            watched.push(format!(".git/{}", reference));
+        }
+    }
+    // A missing path would make cargo rerun this script on every build
+    for path in watched.iter().filter(|p| std::path::Path::new(p).exists()) {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
@@ -0,0 +1,70 @@
+// Fixed-capacity FIFO shared between tasks: once full, each push drops the
+// oldest item. Backs the notification, offline message and RSSI buffers.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+pub struct BoundedBuffer<T> {
+    capacity: usize,
+    items: Mutex<VecDeque<T>>,
+}
+
+impl<T> BoundedBuffer<T> {
+    pub fn new(capacity: usize) -> Self {
+        BoundedBuffer {
+            capacity,
+            items: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Add an item, returning how many are now held and whether the oldest
+    /// had to be dropped to make room. With no capacity the item itself is
+    /// dropped.
+    pub fn push(&self, item: T) -> (usize, bool) {
+        if self.capacity == 0 {
+            return (0, true);
+        }
+        let mut items = self.items.lock().unwrap();
+        let dropped = items.len() == self.capacity;
+        if dropped {
+            items.pop_front();
+        }
+        items.push_back(item);
+        (items.len(), dropped)
+    }
+
+    /// Remove and return everything held, oldest first.
+    pub fn drain(&self) -> Vec<T> {
+        self.items.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl<T: Clone> BoundedBuffer<T> {
+    /// Copy of everything held, oldest first.
+    pub fn items(&self) -> Vec<T> {
+        self.items.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_items_in_order() {
+        let buffer = BoundedBuffer::new(2);
+        assert_eq!(buffer.push(1), (1, false));
+        assert_eq!(buffer.push(2), (2, false));
+        assert_eq!(buffer.push(3), (2, true));
+        assert_eq!(buffer.items(), [2, 3]);
+        assert_eq!(buffer.drain(), [2, 3]);
+        assert!(buffer.drain().is_empty());
+    }
+
+    #[test]
+    fn zero_capacity_holds_nothing() {
+        let buffer = BoundedBuffer::new(0);
+        assert_eq!(buffer.push(1), (0, true));
+        assert!(buffer.items().is_empty());
+    }
+}
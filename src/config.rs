@@ -0,0 +1,158 @@
+// --config files: TOML or JSON with the same keys as the long flags (e.g.
+// `port` or `scan_secs`). Values from the file are turned back into flags
+// and parsed by clap as usual, but only for options not already given on the
+// command line or through the environment.
+
+use anyhow::{bail, Context};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, Command};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Read `path` and return the flags it sets that `matches` left at their
+/// defaults.
+pub fn file_args(
+    cmd: &Command,
+    path: &Path,
+    matches: &ArgMatches,
+) -> anyhow::Result<Vec<OsString>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let is_toml = path.extension().is_some_and(|ext| ext == "toml");
+    let values: serde_json::Value = if is_toml {
+        let table: toml::Table = toml::from_str(&text)
+            .with_context(|| format!("Malformed TOML in {}", path.display()))?;
+        serde_json::to_value(table)?
+    } else {
+        serde_json::from_str(&text)
+            .with_context(|| format!("Malformed JSON in {}", path.display()))?
+    };
+    let serde_json::Value::Object(values) = values else {
+        bail!(
+            "Config file {} must contain an object of options",
+            path.display()
+        );
+    };
+
+    let mut args = Vec::new();
+    for (key, value) in values {
+        let long = key.replace('_', "-");
+        let Some(arg) = cmd
+            .get_arguments()
+            .find(|a| a.get_long() == Some(long.as_str()) && long != "config")
+        else {
+            bail!("Unknown option '{}' in config file {}", key, path.display());
+        };
+        let id = arg.get_id().as_str();
+        if !matches!(
+            matches.value_source(id),
+            None | Some(ValueSource::DefaultValue)
+        ) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        match (arg.get_action(), value) {
+            (_, serde_json::Value::Null) => {}
+            (ArgAction::SetTrue, serde_json::Value::Bool(on)) => {
+                if on {
+                    args.push(flag.into());
+                }
+            }
+            (ArgAction::SetTrue, _) => bail!("Option '{}' must be true or false", key),
+            (ArgAction::Append, serde_json::Value::Array(items)) => {
+                for item in items {
+                    args.push(format!("{}={}", flag, scalar(&key, item)?).into());
+                }
+            }
+            (_, value) => args.push(format!("{}={}", flag, scalar(&key, value)?).into()),
+        }
+    }
+    Ok(args)
+}
+
+fn scalar(key: &str, value: serde_json::Value) -> anyhow::Result<String> {
+    match value {
+        serde_json::Value::String(s) => Ok(s),
+        serde_json::Value::Number(n) => Ok(n.to_string()),
+        serde_json::Value::Bool(b) => Ok(b.to_string()),
+        _ => bail!("Option '{}' must be a string, number or boolean", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn command() -> Command {
+        Command::new("vibekeys_app")
+            .arg(Arg::new("config").long("config"))
+            .arg(Arg::new("port").long("port").default_value("8080"))
+            .arg(Arg::new("scan_secs").long("scan-secs").default_value("5"))
+            .arg(
+                Arg::new("coalesce")
+                    .long("coalesce")
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(
+                Arg::new("cors_origin")
+                    .long("cors-origin")
+                    .action(ArgAction::Append),
+            )
+    }
+
+    // A config file unique to the calling test
+    fn write_config(name: &str, text: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("vibekeys-{}-{}", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    fn file_args_for(path: &Path, cli: &[&str]) -> anyhow::Result<Vec<String>> {
+        let cmd = command();
+        let matches = cmd.clone().get_matches_from(cli);
+        let args = file_args(&cmd, path, &matches);
+        std::fs::remove_file(path).ok();
+        Ok(args?
+            .into_iter()
+            .map(|a| a.into_string().unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn toml_values_become_flags() {
+        let path = write_config(
+            "flags.toml",
+            "port = 9000\nscan_secs = 2\ncoalesce = true\ncors_origin = [\"http://a\", \"http://b\"]\n",
+        );
+        let mut args = file_args_for(&path, &["vibekeys_app"]).unwrap();
+        args.sort();
+        assert_eq!(
+            args,
+            [
+                "--coalesce",
+                "--cors-origin=http://a",
+                "--cors-origin=http://b",
+                "--port=9000",
+                "--scan-secs=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn command_line_wins_over_the_file() {
+        let path = write_config("cli.json", r#"{"port": 9000, "coalesce": false}"#);
+        let args = file_args_for(&path, &["vibekeys_app", "--port", "7000"]).unwrap();
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn unknown_and_mistyped_options_are_errors() {
+        let path = write_config("unknown.json", r#"{"prot": 9000}"#);
+        assert!(file_args_for(&path, &["vibekeys_app"]).is_err());
+        let path = write_config("mistyped.json", r#"{"coalesce": "yes"}"#);
+        assert!(file_args_for(&path, &["vibekeys_app"]).is_err());
+        let path = write_config("nested.json", r#"{"config": "other.json"}"#);
+        assert!(file_args_for(&path, &["vibekeys_app"]).is_err());
+    }
+}
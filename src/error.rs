@@ -0,0 +1,347 @@
+// API error kinds, each with its own HTTP status and machine-readable code
+
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// No device held at all; the status comes from --no-device-status
+    NoDevice(StatusCode),
+    /// The platform reports the device as not connected
+    NotConnected(String),
+    Timeout(String),
+    Unsupported(String),
+    PermissionDenied(String),
+    BadRequest(String),
+    /// A key name not in the key table; the body lists the supported ones
+    UnknownKey {
+        key: String,
+        supported: Vec<String>,
+    },
+    /// Missing or wrong --auth-token
+    Unauthorized,
+    /// Over --rate-limit; sent with a Retry-After header
+    RateLimited {
+        retry_after_secs: u64,
+    },
+    /// The request conflicts with the current state, e.g. nothing to disconnect
+    Conflict(String),
+    /// Dropped without being attempted, e.g. expired in the queue
+    Unavailable(String),
+    /// The device doesn't have the characteristic being written or read
+    NotFound(String),
+    /// A write was larger than the device accepts
+    PayloadTooLarge(String),
+    /// A message over --max-message-bytes, refused before writing
+    MessageTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// Any other BLE failure
+    Ble(String),
+    Internal(String),
+}
+
+impl ApiError {
+    /// Classify a failed BLE operation by its btleplug error, if any.
+    pub fn from_ble(err: &anyhow::Error) -> Self {
+        let message = err.to_string();
+        match err.downcast_ref::<btleplug::Error>() {
+            Some(btleplug::Error::NotConnected) => ApiError::NotConnected(message),
+            Some(btleplug::Error::TimedOut(_)) => ApiError::Timeout(message),
+            Some(btleplug::Error::NotSupported(_)) => ApiError::Unsupported(message),
+            Some(btleplug::Error::PermissionDenied) => ApiError::PermissionDenied(message),
+            _ if err.is::<MissingCharacteristic>() => ApiError::NotFound(message),
+            _ if err.is::<OversizedWrite>() => ApiError::PayloadTooLarge(message),
+            _ => ApiError::Ble(message),
+        }
+    }
+
+    /// Prefix the message, keeping the kind.
+    pub fn context(self, prefix: &str) -> Self {
+        let wrap = |m: String| format!("{}: {}", prefix, m);
+        match self {
+            ApiError::NoDevice(status) => ApiError::NoDevice(status),
+            ApiError::Unauthorized => ApiError::Unauthorized,
+            ApiError::RateLimited { retry_after_secs } => {
+                ApiError::RateLimited { retry_after_secs }
+            }
+            ApiError::NotConnected(m) => ApiError::NotConnected(wrap(m)),
+            ApiError::Timeout(m) => ApiError::Timeout(wrap(m)),
+            ApiError::Unsupported(m) => ApiError::Unsupported(wrap(m)),
+            ApiError::PermissionDenied(m) => ApiError::PermissionDenied(wrap(m)),
+            ApiError::BadRequest(m) => ApiError::BadRequest(wrap(m)),
+            ApiError::UnknownKey { key, supported } => ApiError::UnknownKey { key, supported },
+            ApiError::Conflict(m) => ApiError::Conflict(wrap(m)),
+            ApiError::Unavailable(m) => ApiError::Unavailable(wrap(m)),
+            ApiError::NotFound(m) => ApiError::NotFound(wrap(m)),
+            ApiError::PayloadTooLarge(m) => ApiError::PayloadTooLarge(wrap(m)),
+            ApiError::MessageTooLarge { size, limit } => ApiError::MessageTooLarge { size, limit },
+            ApiError::Ble(m) => ApiError::Ble(wrap(m)),
+            ApiError::Internal(m) => ApiError::Internal(wrap(m)),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NoDevice(status) => *status,
+            ApiError::NotConnected(_) | ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+            ApiError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            ApiError::BadRequest(_) | ApiError::UnknownKey { .. } => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::PayloadTooLarge(_) | ApiError::MessageTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ApiError::Ble(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NoDevice(_) => "no_device",
+            ApiError::NotConnected(_) => "not_connected",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Unsupported(_) => "unsupported",
+            ApiError::PermissionDenied(_) => "permission_denied",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::UnknownKey { .. } => "unknown_key",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::NotFound(_) => "characteristic_not_found",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::MessageTooLarge { .. } => "message_too_large",
+            ApiError::Ble(_) => "ble_error",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::NoDevice(_) => f.write_str("No BLE device connected"),
+            ApiError::Unauthorized => f.write_str("Missing or invalid bearer token"),
+            ApiError::UnknownKey { key, .. } => write!(f, "Unknown key '{}'", key),
+            ApiError::RateLimited { retry_after_secs } => {
+                write!(f, "Too many writes; retry in {}s", retry_after_secs)
+            }
+            ApiError::MessageTooLarge { size, limit } => write!(
+                f,
+                "Message is {} bytes, over the {}-byte limit",
+                size, limit
+            ),
+            ApiError::NotConnected(m)
+            | ApiError::Timeout(m)
+            | ApiError::Unsupported(m)
+            | ApiError::PermissionDenied(m)
+            | ApiError::BadRequest(m)
+            | ApiError::Conflict(m)
+            | ApiError::Unavailable(m)
+            | ApiError::NotFound(m)
+            | ApiError::PayloadTooLarge(m)
+            | ApiError::Ble(m)
+            | ApiError::Internal(m) => f.write_str(m),
+        }
+    }
+}
+
+/// Returned when a write or read targets a characteristic the connected
+/// device doesn't expose, so callers aren't told it succeeded.
+#[derive(Debug)]
+pub struct MissingCharacteristic(pub uuid::Uuid);
+
+impl fmt::Display for MissingCharacteristic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Characteristic {} not found on the device", self.0)
+    }
+}
+
+impl std::error::Error for MissingCharacteristic {}
+
+/// A single write the device rejected for its size, typically because
+/// --chunk-size is larger than the negotiated MTU allows.
+#[derive(Debug)]
+pub struct OversizedWrite {
+    pub size: usize,
+    pub reason: String,
+}
+
+impl OversizedWrite {
+    /// Whether a failed write looks like it was rejected for its length.
+    /// btleplug has no dedicated variant, so this goes by the platform's
+    /// message (e.g. BlueZ's "Invalid Attribute Value Length (0x0d)").
+    pub fn matches(err: &btleplug::Error) -> bool {
+        if matches!(
+            err,
+            btleplug::Error::NotConnected | btleplug::Error::TimedOut(_)
+        ) {
+            return false;
+        }
+        let message = err.to_string().to_lowercase();
+        ["length", "too long", "too large", "0x0d"]
+            .iter()
+            .any(|hint| message.contains(hint))
+    }
+}
+
+impl fmt::Display for OversizedWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Device rejected a {}-byte write as too large ({}); try a lower --chunk-size",
+            self.size, self.reason
+        )
+    }
+}
+
+impl std::error::Error for OversizedWrite {}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut body = serde_json::json!({ "error": self.code(), "message": self.to_string() });
+        if let ApiError::MessageTooLarge { size, limit } = self {
+            body["size"] = size.into();
+            body["limit"] = limit.into();
+        }
+        if let ApiError::RateLimited { retry_after_secs } = self {
+            body["retry_after_secs"] = retry_after_secs.into();
+        }
+        if let ApiError::UnknownKey { supported, .. } = &self {
+            body["supported"] = supported.clone().into();
+        }
+        let mut response = (self.status(), Json(body)).into_response();
+        let headers = response.headers_mut();
+        match self {
+            ApiError::Unauthorized => {
+                headers.insert(
+                    axum::http::header::WWW_AUTHENTICATE,
+                    axum::http::HeaderValue::from_static("Bearer"),
+                );
+            }
+            ApiError::RateLimited { retry_after_secs } => {
+                headers.insert(axum::http::header::RETRY_AFTER, retry_after_secs.into());
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn classify(err: btleplug::Error) -> ApiError {
+        ApiError::from_ble(&err.into())
+    }
+
+    #[test]
+    fn btleplug_errors_map_to_their_own_kinds() {
+        let cases = [
+            (
+                classify(btleplug::Error::NotConnected),
+                "not_connected",
+                503,
+            ),
+            (
+                classify(btleplug::Error::TimedOut(Duration::from_secs(5))),
+                "timeout",
+                504,
+            ),
+            (
+                classify(btleplug::Error::PermissionDenied),
+                "permission_denied",
+                403,
+            ),
+            (
+                classify(btleplug::Error::NotSupported("tx power".into())),
+                "unsupported",
+                501,
+            ),
+            (classify(btleplug::Error::DeviceNotFound), "ble_error", 500),
+        ];
+        for (err, code, status) in cases {
+            assert_eq!((err.code(), err.status().as_u16()), (code, status));
+        }
+    }
+
+    #[test]
+    fn classification_sees_through_context() {
+        let err = anyhow::Error::from(MissingCharacteristic(uuid::Uuid::nil())).context("part 1");
+        let err = ApiError::from_ble(&err);
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        let err = err.context("line 0");
+        assert_eq!(err.code(), "characteristic_not_found");
+        assert!(err.to_string().starts_with("line 0: "));
+    }
+
+    #[test]
+    fn oversized_writes_are_recognised_by_message() {
+        let bluez = btleplug::Error::Other("Invalid Attribute Value Length (0x0d)".into());
+        assert!(OversizedWrite::matches(&bluez));
+        let winrt = btleplug::Error::RuntimeError("The value is too long".into());
+        assert!(OversizedWrite::matches(&winrt));
+        assert!(!OversizedWrite::matches(&btleplug::Error::NotConnected));
+        let other = btleplug::Error::Other("Operation failed".into());
+        assert!(!OversizedWrite::matches(&other));
+    }
+
+    #[test]
+    fn oversized_writes_are_payload_too_large() {
+        let err = anyhow::Error::from(OversizedWrite {
+            size: 244,
+            reason: "0x0d".to_string(),
+        });
+        let err = ApiError::from_ble(&err);
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(err.code(), "payload_too_large");
+    }
+
+    async fn body(response: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn unknown_key_lists_the_supported_keys() {
+        let response = ApiError::UnknownKey {
+            key: "f13".to_string(),
+            supported: vec!["clear".to_string(), "enter".to_string()],
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = body(response).await;
+        assert_eq!(body["error"], "unknown_key");
+        assert_eq!(body["supported"], serde_json::json!(["clear", "enter"]));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_says_when_to_retry() {
+        let response = ApiError::RateLimited {
+            retry_after_secs: 2,
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["retry-after"], "2");
+        assert_eq!(body(response).await["retry_after_secs"], 2);
+    }
+
+    #[test]
+    fn unauthorized_asks_for_a_bearer_token() {
+        let response = ApiError::Unauthorized.into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["www-authenticate"], "Bearer");
+    }
+}
@@ -0,0 +1,99 @@
+// Broadcast feed of sends, connection changes, notifications and errors,
+// streamed by GET /events
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+// Events kept for a slow subscriber before it starts missing them
+const EVENT_BUFFER: usize = 256;
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A write reached the device
+    Sent {
+        message: String,
+        bytes: usize,
+    },
+    Connected {
+        address: String,
+    },
+    Disconnected {
+        address: String,
+    },
+    /// A notification arrived on --notify-uuid
+    Notification {
+        uuid: Uuid,
+        value: String,
+        hex: String,
+    },
+    /// A write failed; `code` matches the HTTP error body
+    Error {
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl Event {
+    /// SSE event name, same as the JSON `type` tag
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Sent { .. } => "sent",
+            Event::Connected { .. } => "connected",
+            Event::Disconnected { .. } => "disconnected",
+            Event::Notification { .. } => "notification",
+            Event::Error { .. } => "error",
+        }
+    }
+}
+
+/// Fan-out of events to every /events subscriber. Publishing never blocks;
+/// with no subscribers events are simply dropped.
+#[derive(Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus {
+            sender: broadcast::channel(EVENT_BUFFER).0,
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        self.sender.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn subscribers_receive_published_events() {
+        let bus = EventBus::default();
+        // Dropped without a subscriber, but publishing still works
+        bus.publish(Event::Connected {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+        });
+        let mut events = bus.subscribe();
+        bus.publish(Event::Sent {
+            message: "hi".to_string(),
+            bytes: 2,
+        });
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.name(), "sent");
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], event.name());
+        assert_eq!(json["bytes"], 2);
+    }
+}
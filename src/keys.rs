@@ -0,0 +1,284 @@
+// Named key -> byte sequence table used by the /key endpoint
+
+use std::collections::{HashMap, HashSet};
+
+// Default bytes written for common keys. Names are matched case-insensitively.
+const DEFAULT_KEYS: &[(&str, &[u8])] = &[
+    ("enter", b"\n"),
+    ("return", b"\r"),
+    ("tab", b"\t"),
+    ("space", b" "),
+    ("backspace", &[0x08]),
+    ("escape", &[0x1b]),
+    ("esc", &[0x1b]),
+    ("delete", &[0x7f]),
+    // Form feed, the usual clear-screen code (Ctrl-L)
+    ("clear", &[0x0c]),
+    ("up", b"\x1b[A"),
+    ("down", b"\x1b[B"),
+    ("right", b"\x1b[C"),
+    ("left", b"\x1b[D"),
+    ("home", b"\x1b[H"),
+    ("end", b"\x1b[F"),
+];
+
+// How a modifier changes the key bytes
+#[derive(Debug, Clone, PartialEq)]
+pub enum ModifierEncoding {
+    /// Turn a single ASCII key into its control code (c -> 0x03)
+    Control,
+    /// ASCII-uppercase the key bytes
+    Upper,
+    /// Write these bytes before the key (alt -> ESC)
+    Prefix(Vec<u8>),
+}
+
+fn default_modifiers() -> HashMap<String, ModifierEncoding> {
+    HashMap::from([
+        ("ctrl".to_string(), ModifierEncoding::Control),
+        ("shift".to_string(), ModifierEncoding::Upper),
+        ("alt".to_string(), ModifierEncoding::Prefix(vec![0x1b])),
+        ("meta".to_string(), ModifierEncoding::Prefix(vec![0x1b])),
+    ])
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+    keys: HashMap<String, Vec<u8>>,
+    modifiers: HashMap<String, ModifierEncoding>,
+}
+
+impl KeyMap {
+    /// Default tables with `overrides` (from `--key-map`) and `modifiers`
+    /// (from `--modifier-map`) applied on top.
+    pub fn new(overrides: &[KeyBinding], modifiers: &[ModifierBinding]) -> Self {
+        let mut keys: HashMap<String, Vec<u8>> = DEFAULT_KEYS
+            .iter()
+            .map(|(name, bytes)| (name.to_string(), bytes.to_vec()))
+            .collect();
+        for binding in overrides {
+            keys.insert(binding.name.clone(), binding.bytes.clone());
+        }
+        let mut modifier_table = default_modifiers();
+        for binding in modifiers {
+            modifier_table.insert(binding.name.clone(), binding.encoding.clone());
+        }
+        KeyMap {
+            keys,
+            modifiers: modifier_table,
+        }
+    }
+
+    /// Bytes for a named key. Single characters not in the table map to
+    /// their own UTF-8 bytes.
+    pub fn get(&self, name: &str) -> Option<Vec<u8>> {
+        if let Some(bytes) = self.named(name) {
+            return Some(bytes);
+        }
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(_), None) => Some(name.as_bytes().to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Bytes for a key in the table only, with no single-character fallback.
+    pub fn named(&self, name: &str) -> Option<Vec<u8>> {
+        self.keys.get(&name.to_lowercase()).cloned()
+    }
+
+    /// Apply `modifiers` to the key bytes. Case-only transforms run first,
+    /// then prefixes are prepended in the order given.
+    pub fn apply_modifiers(&self, key: Vec<u8>, modifiers: &[String]) -> Result<Vec<u8>, String> {
+        let mut seen = HashSet::new();
+        let mut encodings = Vec::with_capacity(modifiers.len());
+        for modifier in modifiers {
+            let name = modifier.to_lowercase();
+            let encoding = self.modifiers.get(&name).ok_or_else(|| {
+                format!(
+                    "Unknown modifier '{}'; supported modifiers: {}",
+                    modifier,
+                    sorted(self.modifiers.keys()).join(", ")
+                )
+            })?;
+            if !seen.insert(name) {
+                return Err(format!("Modifier '{}' given more than once", modifier));
+            }
+            encodings.push((modifier, encoding));
+        }
+
+        let mut bytes = key;
+        if encodings
+            .iter()
+            .any(|(_, e)| **e == ModifierEncoding::Upper)
+        {
+            bytes.make_ascii_uppercase();
+        }
+        if let Some((name, _)) = encodings
+            .iter()
+            .find(|(_, e)| **e == ModifierEncoding::Control)
+        {
+            bytes = match bytes.as_slice() {
+                [b'?'] => vec![0x7f],
+                [b @ (b'@'..=b'_' | b'a'..=b'z')] => vec![b & 0x1f],
+                _ => {
+                    return Err(format!(
+                        "Modifier '{}' only applies to a single ASCII letter or @[\\]^_?",
+                        name
+                    ))
+                }
+            };
+        }
+        let mut out = Vec::new();
+        for (_, encoding) in &encodings {
+            if let ModifierEncoding::Prefix(prefix) = encoding {
+                out.extend_from_slice(prefix);
+            }
+        }
+        out.extend_from_slice(&bytes);
+        Ok(out)
+    }
+
+    /// Sorted list of known key names, for error messages.
+    pub fn names(&self) -> Vec<&str> {
+        sorted(self.keys.keys())
+    }
+}
+
+fn sorted<'a>(names: impl Iterator<Item = &'a String>) -> Vec<&'a str> {
+    let mut names: Vec<&str> = names.map(String::as_str).collect();
+    names.sort_unstable();
+    names
+}
+
+/// One `NAME=HEX` entry from `--key-map`, e.g. `enter=0d0a`.
+#[derive(Debug, Clone)]
+pub struct KeyBinding {
+    name: String,
+    bytes: Vec<u8>,
+}
+
+pub fn parse_key_binding(s: &str) -> Result<KeyBinding, String> {
+    let (name, hex) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=HEX, got '{}'", s))?;
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return Err("key name must not be empty".to_string());
+    }
+    let bytes = parse_hex(hex.trim())?;
+    if bytes.is_empty() {
+        return Err(format!("key '{}' must map to at least one byte", name));
+    }
+    Ok(KeyBinding { name, bytes })
+}
+
+/// One `NAME=ENCODING` entry from `--modifier-map`, where ENCODING is
+/// `control`, `upper`, or a HEX prefix (e.g. `alt=1b`).
+#[derive(Debug, Clone)]
+pub struct ModifierBinding {
+    name: String,
+    encoding: ModifierEncoding,
+}
+
+pub fn parse_modifier_binding(s: &str) -> Result<ModifierBinding, String> {
+    let (name, encoding) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=ENCODING, got '{}'", s))?;
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        return Err("modifier name must not be empty".to_string());
+    }
+    let encoding = match encoding.trim() {
+        "control" => ModifierEncoding::Control,
+        "upper" => ModifierEncoding::Upper,
+        hex => ModifierEncoding::Prefix(parse_hex(hex)?),
+    };
+    Ok(ModifierBinding { name, encoding })
+}
+
+/// Decode a hex string such as `1b5b41`. Whitespace between bytes is ignored.
+pub fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("hex string '{}' has an odd number of digits", s));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|e| e.to_string())?;
+            u8::from_str_radix(pair, 16).map_err(|_| format!("invalid hex byte '{}'", pair))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enter_maps_to_the_configured_bytes() {
+        let keys = KeyMap::new(&[], &[]);
+        assert_eq!(keys.get("Enter"), Some(b"\n".to_vec()));
+        let keys = KeyMap::new(&[parse_key_binding("enter=0d0a").unwrap()], &[]);
+        assert_eq!(keys.get("enter"), Some(b"\r\n".to_vec()));
+    }
+
+    #[test]
+    fn single_characters_map_to_themselves() {
+        let keys = KeyMap::new(&[], &[]);
+        assert_eq!(keys.get("x"), Some(b"x".to_vec()));
+        assert_eq!(keys.get("é"), Some("é".as_bytes().to_vec()));
+        assert_eq!(keys.get("nope"), None);
+    }
+
+    #[test]
+    fn key_bindings_are_validated() {
+        assert!(parse_key_binding("enter").is_err());
+        assert!(parse_key_binding("=0d").is_err());
+        assert!(parse_key_binding("enter=").is_err());
+        assert!(parse_key_binding("enter=0g").is_err());
+        assert_eq!(parse_hex("1b 5b 41"), Ok(vec![0x1b, 0x5b, 0x41]));
+        assert!(parse_hex("1b5").is_err());
+    }
+
+    fn modified(key: &[u8], modifiers: &[&str]) -> Result<Vec<u8>, String> {
+        let modifiers: Vec<String> = modifiers.iter().map(|m| m.to_string()).collect();
+        KeyMap::new(&[], &[]).apply_modifiers(key.to_vec(), &modifiers)
+    }
+
+    #[test]
+    fn ctrl_c_is_0x03() {
+        assert_eq!(modified(b"c", &["ctrl"]), Ok(vec![0x03]));
+        assert_eq!(modified(b"C", &["Ctrl"]), Ok(vec![0x03]));
+        assert_eq!(modified(b"?", &["ctrl"]), Ok(vec![0x7f]));
+        assert!(modified(b"\x1b[A", &["ctrl"]).is_err());
+    }
+
+    #[test]
+    fn shift_and_alt_combine() {
+        assert_eq!(modified(b"a", &["shift"]), Ok(b"A".to_vec()));
+        assert_eq!(modified(b"a", &["alt", "shift"]), Ok(b"\x1bA".to_vec()));
+    }
+
+    #[test]
+    fn unknown_or_repeated_modifiers_are_rejected() {
+        assert!(modified(b"a", &["hyper"]).is_err());
+        assert!(modified(b"a", &["ctrl", "CTRL"]).is_err());
+    }
+
+    #[test]
+    fn modifier_map_overrides_the_defaults() {
+        let keys = KeyMap::new(&[], &[parse_modifier_binding("meta=1b4f").unwrap()]);
+        let bytes = keys.apply_modifiers(b"x".to_vec(), &["meta".to_string()]);
+        assert_eq!(bytes, Ok(b"\x1bOx".to_vec()));
+    }
+
+    #[test]
+    fn named_accepts_only_table_keys() {
+        let keys = KeyMap::new(&[], &[]);
+        assert_eq!(keys.named("Clear"), Some(vec![0x0c]));
+        assert_eq!(keys.named("x"), None);
+        assert!(keys.names().contains(&"clear"));
+    }
+}
@@ -0,0 +1,66 @@
+// Logger setup for --log-format. JSON lines carry the timestamp, level,
+// target and message, plus any key-values attached to the record (the BLE
+// lifecycle logs tag themselves with `event` and `address`).
+
+use log::kv::{Error, Key, Value, VisitSource};
+use std::io::Write;
+
+pub fn init(json: bool) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if json {
+        builder.format(|buf, record| {
+            let timestamp = buf.timestamp_millis().to_string();
+            writeln!(buf, "{}", json_line(timestamp, record))
+        });
+    }
+    builder.init();
+}
+
+fn json_line(timestamp: String, record: &log::Record) -> serde_json::Value {
+    let mut line = serde_json::Map::new();
+    line.insert("timestamp".into(), timestamp.into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    record.key_values().visit(&mut Fields(&mut line)).ok();
+    serde_json::Value::Object(line)
+}
+
+struct Fields<'a>(&'a mut serde_json::Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_lines_include_the_record_key_values() {
+        let fields = [("event", "connected"), ("address", "AA:BB:CC:DD:EE:FF")];
+        let line = json_line(
+            "2024-01-01T00:00:00.000Z".to_string(),
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .target("vibekeys_app")
+                .args(format_args!("Connected"))
+                .key_values(&fields)
+                .build(),
+        );
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "timestamp": "2024-01-01T00:00:00.000Z",
+                "level": "INFO",
+                "target": "vibekeys_app",
+                "message": "Connected",
+                "event": "connected",
+                "address": "AA:BB:CC:DD:EE:FF",
+            })
+        );
+    }
+}
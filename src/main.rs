@@ -1,17 +1,77 @@
 // #![cfg_attr(windows, windows_subsystem = "windows")]
 
-use axum::{extract::State, routing::get, routing::post, Json, Router};
-use btleplug::api::{Central, Manager as _, Peripheral, ScanFilter, WriteType};
-use btleplug::platform::Manager;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::{extract::Query, extract::State, routing::get, routing::post, Json, Router};
+use btleplug::api::{
+    BDAddr, CentralEvent, CentralState, CharPropFlags, Characteristic, ScanFilter, Service,
+    ValueNotification, WriteType,
+};
+// Tests run against in-memory devices instead of the platform's
+#[cfg_attr(test, allow(unused_imports))]
+use btleplug::api::{Central, Manager as _, Peripheral};
+#[cfg(not(test))]
 use btleplug::platform::Peripheral as PlatformPeripheral;
-use clap::Parser;
-use log::{debug, error, info, warn};
+#[cfg(not(test))]
+use btleplug::platform::{Adapter, Manager};
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use futures::StreamExt;
+use log::{debug, error, info, trace, warn};
+#[cfg(test)]
+use mock_ble::{Adapter, Manager, Peripheral as PlatformPeripheral};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::{Future, IntoFuture};
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::broadcast;
 use tokio::time;
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
+mod bounded;
+mod config;
+mod error;
+mod events;
+mod keys;
+mod logging;
+#[cfg(test)]
+mod mock_ble;
+mod notifications;
+mod offline;
+mod queue;
+mod rate_limit;
+mod repeats;
+mod rssi;
+mod saved_state;
+mod scan_log;
+mod sequence;
+mod sessions;
+mod stats;
+mod subscribers;
+mod supervision;
+
+use error::{ApiError, MissingCharacteristic, OversizedWrite};
+use events::{Event, EventBus};
+use keys::{KeyBinding, KeyMap, ModifierBinding};
+use notifications::{Notification, NotificationBuffer};
+use offline::{BufferedWrite, OfflineBuffer};
+use queue::{Priority, WriteJob, WriteQueue, WriteResult};
+use rate_limit::RateLimiter;
+use repeats::RepeatFilter;
+use rssi::RssiHistory;
+use saved_state::SavedState;
+use scan_log::ScanLogThrottle;
+use sequence::Sequence;
+use sessions::Sessions;
+use stats::{Stats, StatsSnapshot};
+use subscribers::Subscribers;
+use supervision::Supervision;
+
 #[cfg(any(target_os = "windows", target_os = "macos"))]
 use tray_icon::{
     menu::{Menu, MenuEvent, MenuItem},
@@ -19,375 +79,6054 @@ use tray_icon::{
 };
 
 /// BLE Controller HTTP Server
+///
+/// Every option can also be set through a `VIBEKEYS_*` environment variable
+/// (shown next to each flag below) or a --config file. Precedence: CLI flag
+/// > environment > config file > default.
 #[derive(Parser, Debug)]
-#[command(version, long_about = None)]
+#[command(version)]
 struct Args {
+    /// Read options from this TOML (.toml) or JSON file, keyed by flag name
+    /// without the dashes, e.g. `port = 8080` or `"scan_secs": 10`
+    #[arg(long, env = "VIBEKEYS_CONFIG")]
+    config: Option<std::path::PathBuf>,
+
     /// HTTP server host address
-    #[arg(long, default_value = "127.0.0.1")]
+    #[arg(long, env = "VIBEKEYS_HOST", default_value = "127.0.0.1")]
     host: String,
 
-    /// HTTP server port
-    #[arg(short, long, default_value_t = 57001)]
+    /// HTTP server port (0 picks a free port)
+    #[arg(short, long, env = "VIBEKEYS_PORT", default_value_t = 57001)]
     port: u16,
-}
 
-// Controller Service UUID
-const CONTROLLER_SERVICE_ID: Uuid = Uuid::from_u128(0x9c80ffb6_affa_4083_944a_91e34c88bd76);
+    /// Require `Authorization: Bearer <token>` on every route except `/`,
+    /// `/version` and `/healthz`; without it the API is open to anyone who
+    /// can reach the port
+    #[arg(
+        long,
+        env = "VIBEKEYS_AUTH_TOKEN",
+        hide_env_values = true,
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    auth_token: Option<String>,
 
-// Keyboard Display Characteristic UUID
-const KEYBOARD_DISPLAY_ID: Uuid = Uuid::from_u128(0xcdaa6472_67a8_4241_93cf_145051608573);
+    /// Browser origins allowed to call the API cross-origin, e.g.
+    /// http://localhost:3000 (repeatable or comma-separated); `*` allows any
+    /// origin. No CORS headers are sent when unset
+    #[arg(long, env = "VIBEKEYS_CORS_ORIGIN", value_delimiter = ',')]
+    cors_origin: Vec<String>,
 
-#[derive(Clone)]
-struct AppState {
-    peripheral: Arc<tokio::sync::Mutex<Option<PlatformPeripheral>>>,
-}
+    /// Write the bound HTTP port to this file once listening
+    #[arg(long, env = "VIBEKEYS_PORT_FILE")]
+    port_file: Option<std::path::PathBuf>,
 
-#[derive(Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
-enum Status {
-    Working,
-    Stopped,
-    Pending,
-}
+    /// Only connect to a device advertising this local name (case-insensitive).
+    /// Combined with --device-address, both must match; --device-priority and
+    /// RSSI then choose among the devices that remain
+    #[arg(long, env = "VIBEKEYS_DEVICE_NAME")]
+    device_name: Option<String>,
 
-#[derive(Deserialize)]
-struct StatusRequest {
-    status: Status,
-}
+    /// Only connect to the device with this address (see --device-name)
+    #[arg(
+        long,
+        env = "VIBEKEYS_DEVICE_ADDRESS",
+        value_parser = |s: &str| BDAddr::from_str_delim(s)
+    )]
+    device_address: Option<BDAddr>,
 
-#[derive(Deserialize)]
-struct SendMessageRequest {
-    message: String,
-}
+    /// Controller service UUID to scan for and write through
+    #[arg(
+        long,
+        env = "VIBEKEYS_SERVICE_UUID",
+        default_value_t = CONTROLLER_SERVICE_ID,
+        value_parser = |s: &str| Uuid::parse_str(s)
+    )]
+    service_uuid: Uuid,
 
-#[cfg(any(target_os = "windows", target_os = "macos"))]
-fn setup_tray() -> anyhow::Result<TrayIcon> {
-    // let quit = MenuItem::new("Quit", true, None);
-    // let quit_id = quit.id().clone();
-    // let menu = Menu::with_items(&[&quit]).unwrap();
-    let menu = Menu::new();
-    // 16x16 RGBA icon - blue circle
-    let icon_data = {
-        let mut data = vec![0u8; 16 * 16 * 4];
-        let center = 7.5f32;
-        let radius = 6.5f32;
-        for y in 0..16 {
-            for x in 0..16 {
-                let dx = x as f32 - center;
-                let dy = y as f32 - center;
-                let dist = (dx * dx + dy * dy).sqrt();
-                let idx = (y * 16 + x) as usize * 4;
-                if dist < radius {
-                    // Blue color with edge anti-aliasing
-                    let alpha = if dist > radius - 1.5 {
-                        ((radius - dist) / 1.5 * 255.0) as u8
-                    } else {
-                        255
-                    };
-                    data[idx] = 50; // R
-                    data[idx + 1] = 120; // G
-                    data[idx + 2] = 255; // B
-                    data[idx + 3] = alpha; // A
-                }
-            }
-        }
-        data
-    };
-    let icon = Icon::from_rgba(icon_data, 16, 16).unwrap();
+    /// Display characteristic UUID that messages are written to
+    #[arg(
+        long,
+        env = "VIBEKEYS_CHAR_UUID",
+        default_value_t = KEYBOARD_DISPLAY_ID,
+        value_parser = |s: &str| Uuid::parse_str(s)
+    )]
+    char_uuid: Uuid,
 
-    let tray_icon = TrayIconBuilder::new()
-        .with_tooltip("Vibe Keys Server")
-        .with_icon(icon)
-        .with_menu(Box::new(menu))
-        .build()
-        .unwrap();
+    /// Maximum number of characteristic writes in flight at once; further
+    /// writes wait for a free slot instead of overrunning the host BLE buffer.
+    /// A message split into chunks is still written alone, so its chunks
+    /// arrive back to back
+    #[arg(
+        long,
+        env = "VIBEKEYS_MAX_INFLIGHT_WRITES",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    max_inflight_writes: u16,
 
-    std::thread::spawn(move || {
-        let tray_event_channel = TrayIconEvent::receiver();
-        let menu_channel = MenuEvent::receiver();
+    /// Add or override a /key mapping as NAME=HEX (e.g. enter=0d0a); repeatable
+    #[arg(
+        long = "key-map",
+        env = "VIBEKEYS_KEY_MAP",
+        value_delimiter = ',',
+        value_parser = keys::parse_key_binding
+    )]
+    key_map: Vec<KeyBinding>,
 
-        loop {
-            if let Ok(event) = tray_event_channel.recv() {
-                println!("tray event: {:?}", event);
-            }
+    /// Add or override a /key modifier as NAME=control|upper|HEX-prefix
+    /// (e.g. alt=1b); repeatable
+    #[arg(
+        long = "modifier-map",
+        env = "VIBEKEYS_MODIFIER_MAP",
+        value_delimiter = ',',
+        value_parser = keys::parse_modifier_binding
+    )]
+    modifier_map: Vec<ModifierBinding>,
 
-            if let Ok(event) = menu_channel.recv() {
-                println!("menu event: {:?}", event);
-                // if event.id == quit_id {
-                //     info!("Quit requested from tray menu");
-                //     std::process::exit(0);
-                // }
-            }
+    /// Delay between characters written by /type, in milliseconds
+    #[arg(long, env = "VIBEKEYS_KEY_DELAY_MS", default_value_t = 50)]
+    key_delay_ms: u64,
 
-            std::thread::sleep(std::time::Duration::from_millis(500));
-        }
-    });
+    /// When no device advertises the controller service, connect to nearby
+    /// devices and look for it after service discovery
+    #[arg(long, env = "VIBEKEYS_PROBE")]
+    probe: bool,
 
-    Ok(tray_icon)
-}
+    /// Maximum number of devices to connect to per scan in --probe mode
+    #[arg(long, env = "VIBEKEYS_MAX_PROBES", default_value_t = 5)]
+    max_probes: usize,
 
-#[cfg(not(any(target_os = "windows", target_os = "macos")))]
-fn setup_tray() -> anyhow::Result<()> {
-    Ok(())
-}
+    /// HTTP status returned when no BLE device is connected
+    #[arg(
+        long,
+        env = "VIBEKEYS_NO_DEVICE_STATUS",
+        value_enum,
+        default_value = "503"
+    )]
+    no_device_status: NoDeviceStatus,
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    /// Message written to the display before a requested /disconnect
+    #[arg(long, env = "VIBEKEYS_DISCONNECT_MESSAGE")]
+    disconnect_message: Option<String>,
 
-    env_logger::init();
+    /// Default characteristic write type
+    #[arg(
+        long,
+        env = "VIBEKEYS_WRITE_TYPE",
+        value_enum,
+        default_value = "with-response"
+    )]
+    write_type: WriteKind,
 
-    // Setup system tray (Windows/macOS only)
-    let _tray = setup_tray()?;
+    /// Treat a device without the display characteristic as a failed
+    /// connection and keep looking, instead of staying connected to it
+    #[arg(long, env = "VIBEKEYS_REQUIRE_CHAR")]
+    require_char: bool,
 
-    let state = AppState {
-        peripheral: Arc::new(tokio::sync::Mutex::new(None)),
-    };
+    /// Times to run service discovery again when it finishes without the
+    /// display characteristic, which some devices only expose on a
+    /// second pass
+    #[arg(long, env = "VIBEKEYS_DISCOVER_RETRIES", default_value_t = 2)]
+    discover_retries: u32,
 
-    let manager = Manager::new().await?;
-    let adapters = manager.adapters().await?;
-    let adapter = adapters
-        .into_iter()
-        .next()
-        .expect("No Bluetooth adapter found");
+    /// Seconds before an unchanged device is logged again at info level
+    /// during scans (every sighting is still logged at debug level)
+    #[arg(long, env = "VIBEKEYS_SCAN_LOG_WINDOW_SECS", default_value_t = 60)]
+    scan_log_window_secs: u64,
 
-    info!("Using adapter: {:?}", adapter.adapter_info().await);
-    info!("Scanning for BLE devices...");
+    /// Split writes into chunks of at most this many bytes, to fit the ATT
+    /// MTU; text is split between characters. 0 writes each message whole
+    #[arg(long, alias = "mtu", env = "VIBEKEYS_CHUNK_SIZE", default_value_t = 20)]
+    chunk_size: u16,
 
-    let mut filter = ScanFilter::default();
-    filter.services.push(CONTROLLER_SERVICE_ID);
+    /// Pause between the chunks of one message, in milliseconds
+    #[arg(long, env = "VIBEKEYS_CHUNK_DELAY_MS", default_value_t = 10)]
+    chunk_delay_ms: u64,
 
-    adapter.start_scan(filter.clone()).await?;
-    time::sleep(Duration::from_secs(5)).await;
+    /// On connect, probe increasingly large writes to find the largest
+    /// chunk the device accepts, falling back to --chunk-size if the probe
+    /// fails
+    #[arg(long, env = "VIBEKEYS_AUTO_MTU")]
+    auto_mtu: bool,
 
-    let peripherals = adapter.peripherals().await?;
-    info!("Found {} devices", peripherals.len());
+    /// Seconds to let in-flight and waiting writes finish on Ctrl-C before
+    /// disconnecting anyway
+    #[arg(long, env = "VIBEKEYS_SHUTDOWN_GRACE_SECS", default_value_t = 5)]
+    shutdown_grace_secs: u64,
 
-    let target = loop {
-        if let Some(p) = find_and_print_peripherals(&peripherals, CONTROLLER_SERVICE_ID).await? {
-            adapter.stop_scan().await?;
-            break p;
-        }
-        adapter.stop_scan().await?;
-        warn!("Target device not found, retrying in 5s...");
-        time::sleep(Duration::from_secs(5)).await;
-        adapter.start_scan(filter.clone()).await?;
-        time::sleep(Duration::from_secs(5)).await;
-    };
+    /// How long each scan runs before the found devices are examined
+    #[arg(long, env = "VIBEKEYS_SCAN_SECS", default_value_t = 5)]
+    scan_secs: u64,
 
-    info!("Connecting to target device...");
-    connect_and_discover(&target).await?;
+    /// Times to ask the adapter for its devices again when a scan ends with
+    /// none listed or the listing fails
+    #[arg(long, env = "VIBEKEYS_PERIPHERALS_RETRIES", default_value_t = 2)]
+    peripherals_retries: u32,
 
-    {
-        let mut peripheral = state.peripheral.lock().await;
-        *peripheral = Some(target);
-    }
+    /// Wait before each --peripherals-retries query, in milliseconds
+    #[arg(long, env = "VIBEKEYS_PERIPHERALS_RETRY_MS", default_value_t = 500)]
+    peripherals_retry_ms: u64,
 
-    info!("Device ready");
+    /// Pause after connecting and after service discovery, for devices that
+    /// aren't ready straight away
+    #[arg(long, env = "VIBEKEYS_CONNECT_SETTLE_SECS", default_value_t = 1)]
+    connect_settle_secs: u64,
 
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        if let Err(e) = ble_monitor_task(state_clone).await {
-            error!("BLE monitor error: {}", e);
-        }
-    });
+    /// Times to try connecting again when a connect attempt fails or hangs,
+    /// before giving up on the device for this scan
+    #[arg(long, env = "VIBEKEYS_CONNECT_RETRIES", default_value_t = 3)]
+    connect_retries: u32,
 
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/send", get(send_message_handler))
-        .route("/send", post(send_message_post))
-        .route("/status", post(status_handler))
-        .with_state(state);
+    /// Give up on a single connect attempt after this many seconds
+    #[arg(
+        long,
+        env = "VIBEKEYS_CONNECT_TIMEOUT_SECS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    connect_timeout_secs: u64,
 
-    let addr = format!("{}:{}", args.host, args.port);
-    let listener = tokio::net::TcpListener::bind(&addr).await?;
-    info!("HTTP server listening on http://{}", addr);
-    axum::serve(listener, app).await?;
+    /// Wait between scans while no device is found or reconnecting fails
+    #[arg(long, env = "VIBEKEYS_RECONNECT_INTERVAL_SECS", default_value_t = 5)]
+    reconnect_interval_secs: u64,
 
-    Ok(())
+    /// Cap for the reconnect wait, which doubles from
+    /// --reconnect-interval-secs after each failed attempt
+    #[arg(long, env = "VIBEKEYS_MAX_RECONNECT_SECS", default_value_t = 60)]
+    max_reconnect_secs: u64,
+
+    /// How long POST /reconnect waits for a device to be connected again
+    #[arg(long, env = "VIBEKEYS_RECONNECT_TIMEOUT_SECS", default_value_t = 30)]
+    reconnect_timeout_secs: u64,
+
+    /// Log a one-line connection summary this often, in seconds
+    #[arg(
+        long,
+        env = "VIBEKEYS_HEALTH_LOG_SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    health_log_secs: Option<u64>,
+
+    /// Verify each /send or /status write by waiting for the device to echo
+    /// the same bytes on this notify characteristic
+    #[arg(long, env = "VIBEKEYS_VERIFY_NOTIFY_UUID")]
+    verify_notify_uuid: Option<Uuid>,
+
+    /// How long to wait for the --verify-notify-uuid echo, in milliseconds
+    #[arg(long, env = "VIBEKEYS_VERIFY_TIMEOUT_MS", default_value_t = 1000)]
+    verify_timeout_ms: u64,
+
+    /// Prefix each write with an incrementing sequence number this many
+    /// bytes wide (1-4, big-endian). With --verify-notify-uuid a write is
+    /// confirmed by a notification starting with its number rather than by
+    /// an echo of the whole message.
+    #[arg(
+        long,
+        env = "VIBEKEYS_SEQUENCE_BYTES",
+        value_parser = clap::value_parser!(u8).range(1..=4)
+    )]
+    sequence_bytes: Option<u8>,
+
+    /// Drop queued writes that waited longer than this many milliseconds
+    /// instead of delivering them late
+    #[arg(long, env = "VIBEKEYS_MESSAGE_TTL_MS")]
+    message_ttl_ms: Option<u64>,
+
+    /// On connect and reconnect, show the last status set via /status
+    /// instead of the greeting
+    #[arg(long, env = "VIBEKEYS_RESTORE_STATUS_ON_CONNECT")]
+    restore_status_on_connect: bool,
+
+    /// Space writes out to at most this many per second, delaying (not
+    /// rejecting) bursts
+    #[arg(long, env = "VIBEKEYS_SMOOTH_RATE", value_parser = clap::value_parser!(u32).range(1..))]
+    smooth_rate: Option<u32>,
+
+    /// With --smooth-rate, collapse messages queued during the wait so only
+    /// the newest is written
+    #[arg(long, env = "VIBEKEYS_COALESCE", requires = "smooth_rate")]
+    coalesce: bool,
+
+    /// Prefer this device address over others as ADDR=PRIORITY (higher wins,
+    /// unlisted devices rank lowest, RSSI breaks ties); repeatable
+    #[arg(
+        long = "device-priority",
+        env = "VIBEKEYS_DEVICE_PRIORITY",
+        value_delimiter = ',',
+        value_parser = parse_device_priority
+    )]
+    device_priority: Vec<(BDAddr, i32)>,
+
+    /// Which instance of the controller service to write to, for devices
+    /// that expose it more than once (0 is the first)
+    #[arg(long, env = "VIBEKEYS_SERVICE_INSTANCE", default_value_t = 0)]
+    service_instance: usize,
+
+    /// Number of RSSI samples kept for /rssi/history (one per monitor tick)
+    #[arg(
+        long,
+        env = "VIBEKEYS_RSSI_HISTORY_SIZE",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    rssi_history_size: u16,
+
+    /// Retry a with-response write as without-response if the device doesn't
+    /// acknowledge it within --write-timeout-ms
+    #[arg(long, env = "VIBEKEYS_FALLBACK_WRITE_TYPE")]
+    fallback_write_type: bool,
+
+    /// Acknowledgement timeout for --fallback-write-type, in milliseconds
+    #[arg(long, env = "VIBEKEYS_WRITE_TIMEOUT_MS", default_value_t = 2000)]
+    write_timeout_ms: u64,
+
+    /// Status strings accepted by POST /status/raw (comma-separated); any
+    /// status is accepted when empty
+    #[arg(long, env = "VIBEKEYS_RAW_STATUS_ALLOW", value_delimiter = ',')]
+    raw_status_allow: Vec<String>,
+
+    /// Write characteristic for a specific device as ADDR=UUID; devices not
+    /// listed use --char-uuid. Repeatable
+    #[arg(
+        long = "device-char",
+        env = "VIBEKEYS_DEVICE_CHAR",
+        value_delimiter = ',',
+        value_parser = parse_device_char
+    )]
+    device_char: Vec<(BDAddr, Uuid)>,
+
+    /// File remembering the last connected device, which startup tries
+    /// before scanning. Nothing is saved without it
+    #[arg(long, env = "VIBEKEYS_STATE_FILE")]
+    state_file: Option<std::path::PathBuf>,
+
+    /// Delete --state-file on startup before doing anything else
+    #[arg(long, env = "VIBEKEYS_RESET_STATE", requires = "state_file")]
+    reset_state: bool,
+
+    /// Exit after --reset-state instead of starting the server
+    #[arg(long, env = "VIBEKEYS_EXIT_AFTER_RESET", requires = "reset_state")]
+    exit_after_reset: bool,
+
+    /// Most writes waiting in the queue; further writes get 503 until it drains
+    #[arg(
+        long,
+        env = "VIBEKEYS_QUEUE_CAPACITY",
+        default_value_t = 256,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    queue_capacity: u32,
+
+    /// Bytes written by GET /ready?probe=true, as HEX; empty sends a
+    /// zero-length write
+    #[arg(
+        long,
+        env = "VIBEKEYS_READY_PROBE_HEX",
+        default_value = "",
+        value_parser = |s: &str| keys::parse_hex(s).map(HexBytes)
+    )]
+    ready_probe_hex: HexBytes,
+
+    /// Timeout for the GET /ready?probe=true write, in milliseconds
+    #[arg(long, env = "VIBEKEYS_READY_PROBE_TIMEOUT_MS", default_value_t = 500)]
+    ready_probe_timeout_ms: u64,
+
+    /// What an empty message does: fail with 400, succeed without writing,
+    /// or write --clear-hex
+    #[arg(
+        long,
+        env = "VIBEKEYS_EMPTY_MESSAGE",
+        value_enum,
+        default_value_t = EmptyMessage::Reject
+    )]
+    empty_message: EmptyMessage,
+
+    /// Bytes that clear the display, as HEX (used by --empty-message clear)
+    #[arg(
+        long,
+        env = "VIBEKEYS_CLEAR_HEX",
+        default_value = "0c",
+        value_parser = |s: &str| keys::parse_hex(s).map(HexBytes)
+    )]
+    clear_hex: HexBytes,
+
+    /// Most write requests (/send, /status, /key, ...) accepted per second
+    /// across all clients; more get 429 with a Retry-After header
+    #[arg(
+        long,
+        env = "VIBEKEYS_RATE_LIMIT",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    rate_limit: Option<u32>,
+
+    /// Longest message /send and /status accept, in bytes; longer ones are
+    /// refused with 413
+    #[arg(long, env = "VIBEKEYS_MAX_MESSAGE_BYTES", default_value_t = 512)]
+    max_message_bytes: usize,
+
+    /// Accept /send and /status while the device is disconnected with 202,
+    /// and write those messages in order once it reconnects
+    #[arg(
+        long,
+        env = "VIBEKEYS_BUFFER_WHILE_DISCONNECTED",
+        conflicts_with = "no_ble"
+    )]
+    buffer_while_disconnected: bool,
+
+    /// Most messages --buffer-while-disconnected holds; the oldest is dropped
+    /// to make room
+    #[arg(
+        long,
+        env = "VIBEKEYS_DISCONNECTED_BUFFER_SIZE",
+        default_value_t = 32,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    disconnected_buffer_size: u16,
+
+    /// Skip /send and /status writes identical to the previous one
+    #[arg(long, env = "VIBEKEYS_COLLAPSE_REPEATS")]
+    collapse_repeats: bool,
+
+    /// With --collapse-repeats, write a repeat anyway once the previous
+    /// write is this many seconds old
+    #[arg(long, env = "VIBEKEYS_COLLAPSE_REFRESH_SECS", default_value_t = 30)]
+    collapse_refresh_secs: u64,
+
+    /// Only write /status and /status/raw when the status differs from the
+    /// last one written (see also --status-keepalive-secs)
+    #[arg(long, env = "VIBEKEYS_STATUS_ON_CHANGE")]
+    status_on_change: bool,
+
+    /// Rewrite the last status when none has been written for this many
+    /// seconds, so displays that blank when idle stay lit
+    #[arg(
+        long,
+        env = "VIBEKEYS_STATUS_KEEPALIVE_SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    status_keepalive_secs: Option<u64>,
+
+    /// Write each line of a message as a separate characteristic write
+    #[arg(long, env = "VIBEKEYS_SPLIT_ON", value_enum)]
+    split_on: Option<SplitOn>,
+
+    /// Maximum number of BLE operations (writes, reads, subscribes, connects)
+    /// running at once, across all endpoints. Also caps
+    /// --max-inflight-writes
+    #[arg(
+        long,
+        env = "VIBEKEYS_MAX_BLE_OPS",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    max_ble_ops: u16,
+
+    /// Subscribe to this characteristic on connect and buffer its
+    /// notifications for GET /events
+    #[arg(long, env = "VIBEKEYS_NOTIFY_UUID", value_parser = |s: &str| Uuid::parse_str(s))]
+    notify_uuid: Option<Uuid>,
+
+    /// Most notifications buffered for GET /events; the oldest are dropped
+    #[arg(
+        long,
+        env = "VIBEKEYS_NOTIFY_BUFFER",
+        default_value_t = 256,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    notify_buffer: u16,
+
+    /// Connect to up to this many of the best matching devices at once and
+    /// keep the first that succeeds
+    #[arg(
+        long,
+        env = "VIBEKEYS_CONNECT_CONCURRENCY",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=4)
+    )]
+    connect_concurrency: u8,
+
+    /// Check the device still reports connected before each write and fail
+    /// fast with 503 if not
+    #[arg(long, env = "VIBEKEYS_VERIFY_BEFORE_WRITE")]
+    verify_before_write: bool,
+
+    /// Reconnect when writes or reads have gone unanswered this long, in
+    /// milliseconds, without waiting for the OS to notice the dead link
+    #[arg(long, env = "VIBEKEYS_SUPERVISION_TIMEOUT_MS")]
+    supervision_timeout_ms: Option<u64>,
+
+    /// Serve the HTTP API without touching Bluetooth, for developing clients
+    /// on machines without an adapter: writes are logged and reported as
+    /// successful, and nothing is ever connected
+    #[arg(long, env = "VIBEKEYS_NO_BLE", alias = "simulate")]
+    no_ble: bool,
+
+    /// Log every event from the Bluetooth adapter at trace level
+    /// (RUST_LOG=vibekeys_app=trace), for diagnosing platform quirks
+    #[arg(long, env = "VIBEKEYS_TRACE_BLE")]
+    trace_ble: bool,
+
+    /// Log output: human-readable lines, or one JSON object per line
+    #[arg(long, env = "VIBEKEYS_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Print a summary of the running configuration at startup; auto shows
+    /// it only when stdout is a terminal and logs aren't JSON
+    #[arg(long, env = "VIBEKEYS_BANNER", value_enum, default_value_t = Banner::Auto)]
+    banner: Banner,
+
+    /// Greeting shown on the first connection after startup
+    #[arg(long, env = "VIBEKEYS_GREETING_CONNECT", default_value = "Connected")]
+    greeting_connect: String,
+
+    /// Greeting shown when reconnecting after the link dropped
+    #[arg(long, env = "VIBEKEYS_GREETING_RECONNECT", default_value = "Connected")]
+    greeting_reconnect: String,
+
+    /// Greeting shown when a connection is requested over HTTP
+    #[arg(long, env = "VIBEKEYS_GREETING_MANUAL", default_value = "Connected")]
+    greeting_manual: String,
+
+    /// Shape of successful JSON responses: as-is, or wrapped as
+    /// {"ok":true,"data":...}. Errors are never wrapped.
+    #[arg(
+        long,
+        env = "VIBEKEYS_RESPONSE_ENVELOPE",
+        value_enum,
+        default_value_t = ResponseEnvelope::Flat
+    )]
+    response_envelope: ResponseEnvelope,
 }
 
-async fn root() -> &'static str {
-    "BLE Controller Service\n"
+// Why connect_and_discover is connecting, which picks the greeting
+#[derive(Clone, Copy, Debug)]
+enum ConnectReason {
+    Initial,
+    Reconnect,
+    Manual,
 }
 
-async fn send_message_handler(
-    State(state): State<AppState>,
-) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    send_to_peripheral(&state, "Hello from HTTP GET!").await
+// How a connect round ended
+#[derive(Clone, Debug)]
+enum RoundOutcome {
+    // `ready` when the device has the display characteristic
+    Connected {
+        address: BDAddr,
+        ready: bool,
+    },
+    Failed {
+        reason: ConnectReason,
+        error: String,
+    },
 }
 
-async fn send_message_post(
-    State(state): State<AppState>,
-    Json(req): Json<SendMessageRequest>,
-) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    send_to_peripheral(&state, &req.message).await
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum LinkPhase {
+    // Scanning for or connecting to a device
+    Connecting,
+    Connected,
+    Disconnected,
 }
 
-async fn send_to_peripheral(
-    state: &AppState,
-    message: &str,
-) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let peripheral = state.peripheral.lock().await;
-    if let Some(ref p) = *peripheral {
-        if let Err(e) = send_message(p, KEYBOARD_DISPLAY_ID, message).await {
-            p.disconnect().await.ok();
-            return Err((axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string()));
+// Bytes given as HEX on the command line. A newtype because clap would
+// treat a plain Vec<u8> as a repeatable flag.
+#[derive(Clone, Debug)]
+struct HexBytes(Vec<u8>);
+
+fn parse_device_char(s: &str) -> Result<(BDAddr, Uuid), String> {
+    let (addr, uuid) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected ADDR=UUID, got '{}'", s))?;
+    let addr = BDAddr::from_str_delim(addr.trim()).map_err(|e| e.to_string())?;
+    let uuid = Uuid::parse_str(uuid.trim()).map_err(|e| e.to_string())?;
+    Ok((addr, uuid))
+}
+
+fn parse_device_priority(s: &str) -> Result<(BDAddr, i32), String> {
+    let (addr, priority) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected ADDR=PRIORITY, got '{}'", s))?;
+    let addr = BDAddr::from_str_delim(addr.trim()).map_err(|e| e.to_string())?;
+    let priority = priority
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid priority '{}'", priority))?;
+    Ok((addr, priority))
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum WriteKind {
+    WithResponse,
+    WithoutResponse,
+}
+
+impl From<WriteKind> for WriteType {
+    fn from(kind: WriteKind) -> Self {
+        match kind {
+            WriteKind::WithResponse => WriteType::WithResponse,
+            WriteKind::WithoutResponse => WriteType::WithoutResponse,
         }
-        Ok(Json(
-            serde_json::json!({ "status": "ok", "message": message }),
-        ))
-    } else {
-        Err((
-            axum::http::StatusCode::SERVICE_UNAVAILABLE,
-            "No BLE device connected".to_string(),
-        ))
     }
 }
 
-async fn status_handler(
-    State(state): State<AppState>,
-    Json(req): Json<StatusRequest>,
-) -> Result<Json<serde_json::Value>, (axum::http::StatusCode, String)> {
-    let message = match req.status {
-        Status::Working => "[working]",
-        Status::Stopped => "[stopped]",
-        Status::Pending => "[pending]",
-    };
-    send_to_peripheral(&state, message).await
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SplitOn {
+    Newline,
 }
 
-// BLE monitor task: watch for disconnect and reconnect
-async fn ble_monitor_task(state: AppState) -> anyhow::Result<()> {
-    let manager = Manager::new().await?;
-    let adapters = manager.adapters().await?;
-    let adapter = adapters
-        .into_iter()
-        .next()
-        .expect("No Bluetooth adapter found");
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
-    let mut interval = tokio::time::interval(Duration::from_secs(2));
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Banner {
+    Auto,
+    Always,
+    Never,
+}
 
-    loop {
-        interval.tick().await;
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ResponseEnvelope {
+    Flat,
+    Wrapped,
+}
 
-        let peripheral = state.peripheral.lock().await;
-        if let Some(ref p) = *peripheral {
-            match p.is_connected().await {
-                Ok(true) => {
-                    debug!("Device connected");
-                }
-                _ => {
-                    warn!("Device disconnected!");
-                    drop(peripheral);
-                    *state.peripheral.lock().await = None;
-
-                    loop {
-                        info!("Scanning for devices...");
-                        adapter.start_scan(ScanFilter::default()).await?;
-                        time::sleep(Duration::from_secs(5)).await;
-
-                        let peripherals = adapter.peripherals().await?;
-                        if let Some(target) =
-                            find_and_print_peripherals(&peripherals, CONTROLLER_SERVICE_ID).await?
-                        {
-                            adapter.stop_scan().await?;
-                            if let Err(e) = connect_and_discover(&target).await {
-                                warn!("Reconnect failed: {}, retrying...", e);
-                                continue;
-                            }
-                            {
-                                let mut p = state.peripheral.lock().await;
-                                *p = Some(target);
-                            }
-                            info!("Reconnected successfully");
-                            break;
-                        }
-                        adapter.stop_scan().await?;
-                        time::sleep(Duration::from_secs(5)).await;
-                    }
-                }
-            }
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum EmptyMessage {
+    Reject,
+    Noop,
+    Clear,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum NoDeviceStatus {
+    #[value(name = "503")]
+    ServiceUnavailable,
+    #[value(name = "425")]
+    TooEarly,
+    #[value(name = "404")]
+    NotFound,
+}
+
+impl NoDeviceStatus {
+    fn code(self) -> axum::http::StatusCode {
+        match self {
+            NoDeviceStatus::ServiceUnavailable => axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            NoDeviceStatus::TooEarly => axum::http::StatusCode::TOO_EARLY,
+            NoDeviceStatus::NotFound => axum::http::StatusCode::NOT_FOUND,
         }
     }
 }
 
-// Find and list peripherals with target service
-async fn find_and_print_peripherals(
-    peripherals: &[PlatformPeripheral],
-    target_service: Uuid,
-) -> anyhow::Result<Option<PlatformPeripheral>> {
-    let mut result = None;
+// Default controller service UUID (--service-uuid)
+const CONTROLLER_SERVICE_ID: Uuid = Uuid::from_u128(0x9c80ffb6_affa_4083_944a_91e34c88bd76);
 
-    for peripheral in peripherals {
-        let addr = peripheral.address();
-        if let Some(props) = peripheral.properties().await? {
-            let name = props.local_name.unwrap_or("(unknown)".to_string());
-            let rssi = props.rssi.unwrap_or(0);
-            info!("  {} - {} (RSSI: {})", addr, name, rssi);
+// Default keyboard display characteristic UUID (--char-uuid)
+const KEYBOARD_DISPLAY_ID: Uuid = Uuid::from_u128(0xcdaa6472_67a8_4241_93cf_145051608573);
 
-            for service in &props.services {
-                debug!("    Service UUID: {}", service);
-            }
+#[derive(Clone)]
+struct AppState {
+    args: Arc<Args>,
+    peripheral: Arc<tokio::sync::Mutex<Option<PlatformPeripheral>>>,
+    // One permit per write allowed in flight (see --max-inflight-writes)
+    write_slots: Arc<tokio::sync::Semaphore>,
+    // Held for the whole of a multi-write sequence so concurrent requests
+    // can't interleave their parts on the wire
+    write_order: Arc<tokio::sync::Mutex<()>>,
+    // The writer takes this exclusively for a message written in chunks and
+    // shared otherwise, so with --max-inflight-writes above 1 no other write
+    // lands between one message's chunks
+    chunk_order: Arc<tokio::sync::RwLock<()>>,
+    keys: Arc<KeyMap>,
+    key_delay: Duration,
+    scan_log: Arc<std::sync::Mutex<ScanLogThrottle>>,
+    // Effective write chunk size in bytes, 0 for unchunked (see --chunk-size)
+    chunk_size: Arc<AtomicUsize>,
+    write_queue: Arc<WriteQueue>,
+    stats: Arc<Stats>,
+    // Most recent status written via /status or /status/raw, framed (see
+    // --restore-status-on-connect)
+    last_status: Arc<std::sync::Mutex<Option<String>>>,
+    rssi_history: Arc<RssiHistory>,
+    events: Arc<EventBus>,
+    // Characteristics subscribed through POST /subscribe on the current link
+    subscriptions: Arc<std::sync::Mutex<BTreeSet<Uuid>>>,
+    supervision: Arc<Supervision>,
+    subscribers: Arc<Subscribers>,
+    // Shared by startup, the monitor and POST /reconnect; scans go through
+    // Scan so they don't overlap. None with --no-ble
+    adapter: Option<Adapter>,
+    // Held for the duration of each scan (see Scan)
+    scan_lock: Arc<tokio::sync::Mutex<()>>,
+    ble_ops: Arc<tokio::sync::Semaphore>,
+    // Set by /disconnect so the monitor doesn't reconnect on its own
+    user_disconnected: Arc<AtomicBool>,
+    // POST /reconnect asking the monitor to scan and connect again
+    reconnect_requested: Arc<tokio::sync::Notify>,
+    // How each connect round ended, so POST /reconnect can answer
+    round_outcomes: broadcast::Sender<RoundOutcome>,
+    // Where the link stands, shown by GET /status. A write failing with
+    // NotConnected sets Disconnected even if is_connected still says true.
+    phase: Arc<std::sync::Mutex<LinkPhase>>,
+    // Asks the monitor to check the link now rather than at its next tick
+    link_check: Arc<tokio::sync::Notify>,
+    // The device most recently connected, looked for first when reconnecting
+    last_device: Arc<std::sync::Mutex<Option<PreviousDevice>>>,
+    notifications: Arc<NotificationBuffer>,
+    // Consumer of --notify-uuid notifications for the current connection
+    notify_task: Arc<std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>>,
+    // Counter for --sequence-bytes
+    sequence: Option<Arc<Sequence>>,
+    // When each device last took a write, to prefer it when reconnecting
+    last_write_ok: Arc<std::sync::Mutex<std::collections::HashMap<BDAddr, std::time::Instant>>>,
+    // Last message written, for --collapse-repeats
+    repeats: Option<Arc<std::sync::Mutex<RepeatFilter>>>,
+    // Last status written, for --status-on-change and --status-keepalive-secs
+    status_writes: Arc<std::sync::Mutex<RepeatFilter>>,
+    sessions: Arc<Sessions>,
+    // Shared by all clients (see --rate-limit)
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Messages held while disconnected (see --buffer-while-disconnected)
+    offline: Option<Arc<OfflineBuffer>>,
+}
 
-            let has_target_service = props.services.iter().any(|s| *s == target_service);
+impl AppState {
+    fn adapter(&self) -> anyhow::Result<&Adapter> {
+        self.adapter
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Bluetooth is disabled (--no-ble)"))
+    }
 
-            if has_target_service {
-                info!("    >>> Found target service!");
-                if result.is_none() {
-                    result = Some(peripheral.clone());
-                }
-            }
+    fn display_target(&self, addr: BDAddr) -> CharTarget {
+        CharTarget {
+            service: self.args.service_uuid,
+            characteristic: self.display_char(addr),
+            instance: self.args.service_instance,
+        }
+    }
 
-            debug!("----------------------------");
+    // --device-char entry for this device, else --char-uuid
+    fn display_char(&self, addr: BDAddr) -> Uuid {
+        self.args
+            .device_char
+            .iter()
+            .find(|(a, _)| *a == addr)
+            .map_or(self.args.char_uuid, |(_, uuid)| *uuid)
+    }
+
+    // Permit for one btleplug operation (see --max-ble-ops). The semaphore
+    // is never closed, so this only returns None in theory.
+    async fn ble_op(&self) -> Option<tokio::sync::SemaphorePermit<'_>> {
+        self.ble_ops.acquire().await.ok()
+    }
+
+    fn greeting(&self, reason: ConnectReason) -> &str {
+        match reason {
+            ConnectReason::Initial => &self.args.greeting_connect,
+            ConnectReason::Reconnect => &self.args.greeting_reconnect,
+            ConnectReason::Manual => &self.args.greeting_manual,
         }
     }
 
-    Ok(result)
+    // Written once connected: the last status with
+    // --restore-status-on-connect, else the greeting
+    fn connect_message(&self, reason: ConnectReason) -> String {
+        let restored = match self.args.restore_status_on_connect {
+            true => self.last_status.lock().unwrap().clone(),
+            false => None,
+        };
+        restored.unwrap_or_else(|| self.greeting(reason).to_string())
+    }
+
+    fn write_options(&self, write_type: WriteType) -> WriteOptions {
+        WriteOptions {
+            write_type,
+            chunk_size: self.chunk_size(),
+            chunk_delay: Duration::from_millis(self.args.chunk_delay_ms),
+            fallback_after: self
+                .args
+                .fallback_write_type
+                .then(|| Duration::from_millis(self.args.write_timeout_ms)),
+        }
+    }
+
+    fn chunk_size(&self) -> Option<usize> {
+        match self.chunk_size.load(Ordering::Relaxed) {
+            0 => None,
+            n => Some(n),
+        }
+    }
 }
 
-// Connect to device and discover services
-async fn connect_and_discover(peripheral: &PlatformPeripheral) -> anyhow::Result<()> {
-    let addr = peripheral.address();
-    info!("Connecting to {}...", addr);
+#[derive(Clone, Copy, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Working,
+    Stopped,
+    Pending,
+}
 
-    peripheral.connect().await?;
-    time::sleep(Duration::from_secs(1)).await;
+// Longest status accepted by POST /status/raw, before framing
+const MAX_RAW_STATUS_LEN: usize = 32;
 
-    info!("Discovering services...");
-    peripheral.discover_services().await?;
-    time::sleep(Duration::from_secs(1)).await;
+// Status text as the display expects it, e.g. "working" -> "[working]"
+fn frame_status(status: &str) -> String {
+    format!("[{}]", status)
+}
 
-    let characteristics = peripheral.characteristics();
-    info!("Found {} characteristics", characteristics.len());
+impl Status {
+    fn message(self) -> &'static str {
+        match self {
+            Status::Working => "[working]",
+            Status::Stopped => "[stopped]",
+            Status::Pending => "[pending]",
+        }
+    }
+}
 
-    // Send "Connected" message after successful connection
-    send_message(peripheral, KEYBOARD_DISPLAY_ID, "Connected").await?;
+#[derive(Deserialize, JsonSchema)]
+struct StatusRequest {
+    status: Status,
+}
 
-    Ok(())
+#[derive(Deserialize, JsonSchema)]
+struct RawStatusRequest {
+    status: String,
 }
 
-// Send message to characteristic
-async fn send_message(
-    peripheral: &PlatformPeripheral,
-    char_uuid: Uuid,
-    message: &str,
-) -> anyhow::Result<()> {
-    let characteristics = peripheral.characteristics();
+#[derive(Deserialize, JsonSchema)]
+struct SendMessageRequest {
+    message: String,
+    /// Overrides --write-type for this message
+    write_type: Option<WriteKind>,
+}
 
-    for char in characteristics {
-        if char.uuid == char_uuid {
-            info!("Found target characteristic: {}", char.uuid);
-            info!("Sending data: {}", message);
+/// Exactly one of `hex` or `base64`
+#[derive(Deserialize, JsonSchema)]
+struct SendRawRequest {
+    hex: Option<String>,
+    base64: Option<String>,
+    /// Overrides --write-type for this message
+    write_type: Option<WriteKind>,
+}
 
-            peripheral
-                .write(&char, message.as_bytes(), WriteType::WithResponse)
-                .await?;
+#[derive(Deserialize, JsonSchema)]
+struct SendBatchRequest {
+    messages: Vec<BatchMessage>,
+}
 
-            info!("Data sent successfully");
-            return Ok(());
-        }
-    }
+#[derive(Deserialize, JsonSchema)]
+struct BatchMessage {
+    message: String,
+    /// Overrides --write-type for this element
+    write_type: Option<WriteKind>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ReadBatchRequest {
+    characteristics: Vec<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct SendQuery {
+    #[serde(default)]
+    sync: bool,
+}
+
+#[derive(Deserialize)]
+struct ReadyQuery {
+    #[serde(default)]
+    probe: bool,
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct SubscribeRequest {
+    characteristic: Uuid,
+}
 
-    Err(anyhow::anyhow!("Characteristic {} not found", char_uuid))
+#[derive(Deserialize, JsonSchema)]
+struct TypeRequest {
+    text: String,
+}
+
+// A key is given either by name (looked up in the key map) or as a raw code
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+enum KeyId {
+    Name(String),
+    Code(u8),
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct KeyRequest {
+    key: KeyId,
+    #[serde(default)]
+    modifiers: Vec<String>,
+}
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+type Tray = TrayIcon;
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+type Tray = ();
+
+// Tray icon colours (RGB) for connected and disconnected
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+const TRAY_CONNECTED: [u8; 3] = [60, 200, 90];
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+const TRAY_DISCONNECTED: [u8; 3] = [150, 150, 150];
+
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn setup_tray(quit_requested: Arc<tokio::sync::Notify>) -> anyhow::Result<Tray> {
+    let quit = MenuItem::new("Quit", true, None);
+    let quit_id = quit.id().clone();
+    let menu = Menu::with_items(&[&quit])?;
+
+    // Starts out disconnected; refresh_tray updates it as the link changes
+    let tray_icon = TrayIconBuilder::new()
+        .with_tooltip("Vibe Keys Server: Disconnected")
+        .with_icon(tray_icon_image(TRAY_DISCONNECTED))
+        .with_menu(Box::new(menu))
+        .build()
+        .unwrap();
+
+    std::thread::spawn(move || {
+        let tray_event_channel = TrayIconEvent::receiver();
+        let menu_channel = MenuEvent::receiver();
+
+        // Poll both channels; a blocking recv on one would starve the other
+        loop {
+            while let Ok(event) = tray_event_channel.try_recv() {
+                debug!("tray event: {:?}", event);
+            }
+
+            while let Ok(event) = menu_channel.try_recv() {
+                debug!("menu event: {:?}", event);
+                if event.id == quit_id {
+                    info!("Quit requested from tray menu");
+                    // Stored as a permit if main isn't waiting yet
+                    quit_requested.notify_one();
+                }
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        }
+    });
+
+    Ok(tray_icon)
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn setup_tray(_quit_requested: Arc<tokio::sync::Notify>) -> anyhow::Result<Tray> {
+    Ok(())
+}
+
+// 16x16 RGBA icon - a circle in `rgb`
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn tray_icon_image([r, g, b]: [u8; 3]) -> Icon {
+    let icon_data = {
+        let mut data = vec![0u8; 16 * 16 * 4];
+        let center = 7.5f32;
+        let radius = 6.5f32;
+        for y in 0..16 {
+            for x in 0..16 {
+                let dx = x as f32 - center;
+                let dy = y as f32 - center;
+                let dist = (dx * dx + dy * dy).sqrt();
+                let idx = (y * 16 + x) as usize * 4;
+                if dist < radius {
+                    // Edge anti-aliasing
+                    let alpha = if dist > radius - 1.5 {
+                        ((radius - dist) / 1.5 * 255.0) as u8
+                    } else {
+                        255
+                    };
+                    data[idx] = r;
+                    data[idx + 1] = g;
+                    data[idx + 2] = b;
+                    data[idx + 3] = alpha;
+                }
+            }
+        }
+        data
+    };
+    Icon::from_rgba(icon_data, 16, 16).unwrap()
+}
+
+// Show the connection in the tray tooltip and icon colour. `device` is the
+// connected device's name (or address), None when disconnected.
+#[cfg(any(target_os = "windows", target_os = "macos"))]
+fn show_tray_status(tray: &Tray, device: Option<&str>) {
+    let (status, color) = match device {
+        Some(name) => (format!("Connected: {}", name), TRAY_CONNECTED),
+        None => ("Disconnected".to_string(), TRAY_DISCONNECTED),
+    };
+    if let Err(e) = tray.set_tooltip(Some(format!("Vibe Keys Server: {}", status))) {
+        warn!("Failed to update tray tooltip: {}", e);
+    }
+    if let Err(e) = tray.set_icon(Some(tray_icon_image(color))) {
+        warn!("Failed to update tray icon: {}", e);
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn show_tray_status(_tray: &Tray, _device: Option<&str>) {}
+
+// Show the current connection in the tray
+async fn refresh_tray(tray: &Tray, state: &AppState) {
+    let peripheral = state.peripheral.lock().await.clone();
+    let device = match peripheral {
+        Some(p) => {
+            let name = p
+                .properties()
+                .await
+                .ok()
+                .flatten()
+                .and_then(|props| props.local_name);
+            Some(name.unwrap_or_else(|| p.address().to_string()))
+        }
+        None => None,
+    };
+    show_tray_status(tray, device.as_deref());
+}
+
+// Startup failures (no adapter, port in use, ...) are reported as one line
+// and a non-zero exit code rather than anyhow's debug output, which includes
+// a backtrace whenever RUST_BACKTRACE is set
+#[tokio::main]
+async fn main() -> std::process::ExitCode {
+    match run().await {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            std::process::ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run() -> anyhow::Result<()> {
+    let args = Arc::new(parse_args()?);
+
+    logging::init(args.log_format == LogFormat::Json);
+    if args.auth_token.is_none() {
+        warn!("No --auth-token set; the HTTP API is unauthenticated");
+    }
+    let cors = cors_layer(&args.cors_origin)?;
+
+    if let (true, Some(path)) = (args.reset_state, &args.state_file) {
+        reset_state(path)?;
+        if args.exit_after_reset {
+            return Ok(());
+        }
+    }
+
+    // Setup system tray (Windows/macOS only)
+    // Quit from the tray menu takes the same graceful path as Ctrl-C
+    let quit_requested = Arc::new(tokio::sync::Notify::new());
+    #[allow(clippy::let_unit_value)]
+    let tray = setup_tray(quit_requested.clone())?;
+
+    // One adapter handle for the whole app; a second one from the monitor
+    // made BlueZ report the adapter busy
+    let adapter = if args.no_ble {
+        warn!("Running without Bluetooth (--no-ble); writes are only logged");
+        None
+    } else {
+        let manager = Manager::new()
+            .await
+            .map_err(|e| anyhow::anyhow!("Bluetooth is unavailable ({}); is it enabled?", e))?;
+        let adapters = manager.adapters().await?;
+        let Some(adapter) = adapters.into_iter().next() else {
+            anyhow::bail!("No Bluetooth adapter found; is Bluetooth enabled?");
+        };
+        Some(adapter)
+    };
+
+    let state = app_state(args.clone(), adapter.clone());
+    tokio::spawn(writer_task(state.clone()));
+    if args.status_keepalive_secs.is_some() {
+        tokio::spawn(status_keepalive_task(state.clone()));
+    }
+
+    let adapter_info = match &adapter {
+        Some(adapter) => {
+            start_ble_trace(&args, adapter).await?;
+            let adapter_info = adapter
+                .adapter_info()
+                .await
+                .unwrap_or_else(|e| format!("unknown ({})", e));
+            info!("Using adapter: {}", adapter_info);
+            ensure_powered_on(adapter).await?;
+            adapter_info
+        }
+        None => "none (--no-ble)".to_string(),
+    };
+
+    let app = router(state.clone());
+    // Outermost, so preflight requests are answered before --auth-token applies
+    let app = match cors {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
+
+    let listener = bind(&args).await?;
+    let bound = listener.local_addr()?;
+    let plain_output = std::io::stdout().is_terminal() && args.log_format == LogFormat::Text;
+    if show_banner(args.banner, plain_output) {
+        print_banner(&args, &adapter_info, bound);
+    }
+    let shutdown = shutdown_signal(&quit_requested);
+    tokio::pin!(shutdown);
+
+    // Stop accepting connections on Ctrl-C or SIGTERM, but let requests
+    // already waiting on a write finish within the grace period
+    let stop = Arc::new(tokio::sync::Notify::new());
+    let stop_server = stop.clone();
+    let mut server = tokio::spawn(
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { stop_server.notified().await })
+            .into_future(),
+    );
+
+    // Connect in the background, so /healthz and /status answer (the latter
+    // with "connected": false) while the first scan is still running. Set
+    // stop_monitor on shutdown; it also ends a scan in progress early.
+    let (stop_monitor, monitor_stopped) = tokio::sync::watch::channel(false);
+    let monitor = adapter.is_some().then(|| {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = ble_monitor_task(state, monitor_stopped).await {
+                error!("BLE monitor error: {}", e);
+            }
+        })
+    });
+    // The tray lives on this thread, so connection changes are shown from
+    // here rather than from the monitor task
+    let mut link_events = state.events.subscribe();
+    loop {
+        tokio::select! {
+            result = &mut server => return Ok(result??),
+            result = &mut shutdown => break result?,
+            event = link_events.recv() => match event {
+                // The device is held by the time Connected is published
+                Ok(Event::Connected { .. }) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                    refresh_tray(&tray, &state).await
+                }
+                Ok(Event::Disconnected { .. }) => show_tray_status(&tray, None),
+                _ => {}
+            },
+        }
+    }
+
+    // Stop any scan now, so the adapter isn't left scanning (which can block
+    // other apps) and a startup scan doesn't hold up the exit
+    stop_monitor.send(true).ok();
+
+    let grace = Duration::from_secs(args.shutdown_grace_secs);
+    info!(
+        "Shutting down, waiting up to {:?} for pending writes...",
+        grace
+    );
+    stop.notify_one();
+    let deadline = time::Instant::now() + grace;
+    let drained = async {
+        server.await??;
+        writes_drained(&state).await;
+        anyhow::Ok(())
+    };
+    match time::timeout_at(deadline, drained).await {
+        Ok(result) => result?,
+        Err(_) => warn!("Grace period expired with writes still pending"),
+    }
+
+    if let Some(monitor) = monitor {
+        if time::timeout(Duration::from_secs(2), monitor)
+            .await
+            .is_err()
+        {
+            warn!("BLE monitor did not stop in time");
+        }
+    }
+
+    if let Some(task) = state.notify_task.lock().unwrap().take() {
+        task.abort();
+    }
+
+    // Nothing else may be written once the farewell message is
+    let _order = state.write_order.lock().await;
+    if let Some(p) = state.peripheral.lock().await.take() {
+        // Tell the display, so it doesn't wait for the link supervision
+        // timeout to notice we're gone
+        let _op = state.ble_op().await;
+        if let Err(e) = send_message(
+            &p,
+            state.display_target(p.address()),
+            SHUTDOWN_MESSAGE.as_bytes(),
+            state.write_options(WriteType::WithResponse),
+        )
+        .await
+        {
+            warn!("Failed to send shutdown message: {}", e);
+        }
+        info!("Disconnecting from {}...", p.address());
+        p.disconnect().await.ok();
+    }
+
+    Ok(())
+}
+
+// Shared state for the handlers and background tasks; `adapter` is None
+// with --no-ble
+fn app_state(args: Arc<Args>, adapter: Option<Adapter>) -> AppState {
+    AppState {
+        args: args.clone(),
+        adapter,
+        peripheral: Arc::new(tokio::sync::Mutex::new(None)),
+        write_slots: Arc::new(tokio::sync::Semaphore::new(
+            args.max_inflight_writes as usize,
+        )),
+        write_order: Arc::new(tokio::sync::Mutex::new(())),
+        chunk_order: Arc::new(tokio::sync::RwLock::new(())),
+        keys: Arc::new(KeyMap::new(&args.key_map, &args.modifier_map)),
+        key_delay: Duration::from_millis(args.key_delay_ms),
+        scan_log: Arc::new(std::sync::Mutex::new(ScanLogThrottle::new(
+            Duration::from_secs(args.scan_log_window_secs),
+        ))),
+        chunk_size: Arc::new(AtomicUsize::new(args.chunk_size.into())),
+        write_queue: Arc::new(WriteQueue::new(args.queue_capacity as usize)),
+        stats: Arc::new(Stats::default()),
+        last_status: Arc::new(std::sync::Mutex::new(None)),
+        rssi_history: Arc::new(RssiHistory::new(args.rssi_history_size.into())),
+        events: Arc::new(EventBus::default()),
+        subscriptions: Arc::new(std::sync::Mutex::new(BTreeSet::new())),
+        supervision: Arc::new(Supervision::default()),
+        subscribers: Arc::new(Subscribers::default()),
+        scan_lock: Arc::new(tokio::sync::Mutex::new(())),
+        ble_ops: Arc::new(tokio::sync::Semaphore::new(args.max_ble_ops as usize)),
+        user_disconnected: Arc::new(AtomicBool::new(false)),
+        reconnect_requested: Arc::new(tokio::sync::Notify::new()),
+        round_outcomes: broadcast::channel(8).0,
+        phase: Arc::new(std::sync::Mutex::new(if args.no_ble {
+            LinkPhase::Disconnected
+        } else {
+            LinkPhase::Connecting
+        })),
+        link_check: Arc::new(tokio::sync::Notify::new()),
+        last_device: Arc::new(std::sync::Mutex::new(None)),
+        notifications: Arc::new(NotificationBuffer::new(args.notify_buffer.into())),
+        notify_task: Arc::new(std::sync::Mutex::new(None)),
+        sequence: args
+            .sequence_bytes
+            .map(|width| Arc::new(Sequence::new(width))),
+        sessions: Arc::new(Sessions::default()),
+        offline: args
+            .buffer_while_disconnected
+            .then(|| Arc::new(OfflineBuffer::new(args.disconnected_buffer_size.into()))),
+        rate_limiter: args
+            .rate_limit
+            .map(|per_sec| Arc::new(RateLimiter::new(per_sec, std::time::Instant::now()))),
+        last_write_ok: Arc::new(std::sync::Mutex::new(std::collections::HashMap::new())),
+        status_writes: Arc::new(std::sync::Mutex::new(RepeatFilter::new(
+            args.status_keepalive_secs
+                .map_or(Duration::MAX, Duration::from_secs),
+        ))),
+        repeats: args.collapse_repeats.then(|| {
+            Arc::new(std::sync::Mutex::new(RepeatFilter::new(
+                Duration::from_secs(args.collapse_refresh_secs),
+            )))
+        }),
+    }
+}
+
+// Every route with the middleware (CORS, being optional, is added by run)
+fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/", get(root))
+        .route("/version", get(version_handler))
+        .route("/schema", get(schema_handler))
+        .route("/stats", get(stats_handler))
+        .route("/write-config", get(write_config_handler))
+        .route("/device", get(device_handler))
+        .route("/ready", get(ready_handler))
+        .route("/healthz", get(healthz))
+        .route("/rssi/history", get(rssi_history_handler))
+        .route("/events", get(events_handler))
+        .route("/subscribers", get(subscribers_handler))
+        .route(
+            "/session",
+            get(sessions_handler).post(create_session_handler),
+        )
+        .route("/send", get(send_message_handler))
+        .route("/send", post(send_message_post))
+        .route("/send/batch", post(send_batch_handler))
+        .route("/send/raw", post(send_raw_handler))
+        .route(
+            "/status",
+            get(connection_status_handler).post(status_handler),
+        )
+        .route("/status/raw", post(raw_status_handler))
+        .route("/key", post(key_handler))
+        .route("/send/key", post(send_key_handler))
+        .route("/type", post(type_handler))
+        .route("/read/batch", post(read_batch_handler))
+        .route("/subscribe", post(subscribe_handler))
+        .route("/unsubscribe", post(unsubscribe_handler))
+        .route("/disconnect", post(disconnect_handler))
+        .route("/reconnect", post(reconnect_handler))
+        .route(
+            "/tx-power",
+            get(tx_power_handler).post(set_tx_power_handler),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            require_token,
+        ))
+        .layer(axum::middleware::map_response_with_state(
+            state.clone(),
+            response_envelope,
+        ))
+        .layer(axum::middleware::map_response(version_header))
+        .with_state(state)
+}
+
+// Bind --host and --port, reporting the port actually bound (see --port 0
+// and --port-file)
+async fn bind(args: &Args) -> anyhow::Result<tokio::net::TcpListener> {
+    let addr = format!("{}:{}", args.host, args.port);
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    let bound = listener.local_addr()?;
+    info!("HTTP server listening on http://{}", bound);
+    if args.port == 0 {
+        // Shown even without RUST_LOG so launchers can pick it up
+        println!("Listening on port {}", bound.port());
+    }
+    if let Some(ref path) = args.port_file {
+        std::fs::write(path, format!("{}\n", bound.port()))?;
+        info!("Wrote port {} to {}", bound.port(), path.display());
+    }
+    Ok(listener)
+}
+
+// Returns once the queue is empty and every write slot has been given back
+async fn writes_drained(state: &AppState) {
+    let slots = state.args.max_inflight_writes as usize;
+    while state.write_queue.depth() > 0 || state.write_slots.available_permits() < slots {
+        time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// Find and connect to the first device, the saved one if it can be reached
+// directly, retrying until one connects. None when shutdown was requested
+// first.
+async fn connect_at_startup(
+    state: &AppState,
+    mut stop: tokio::sync::watch::Receiver<bool>,
+) -> Option<PlatformPeripheral> {
+    let args = &state.args;
+    let saved = saved_device(args);
+    if let Some(saved) = &saved {
+        if let Some(p) = connect_saved_device(state, saved).await {
+            return Some(p);
+        }
+    }
+
+    info!(event = "scan_start"; "Scanning for BLE devices...");
+    let retry_interval = Duration::from_secs(args.reconnect_interval_secs);
+    loop {
+        let attempt =
+            scan_and_connect(state, ConnectReason::Initial, saved.as_ref(), &mut stop).await;
+        match attempt {
+            // Most likely transient (e.g. the adapter is busy), so retried
+            Err(e) => warn!(
+                "Scan failed, retrying in {}s: {:#}",
+                args.reconnect_interval_secs, e
+            ),
+            Ok(Attempt::Connected(p)) => return Some(p),
+            Ok(Attempt::Failed(addr, e)) if args.require_char => warn!("Rejected {}: {}", addr, e),
+            Ok(Attempt::Failed(addr, e)) => warn!(
+                "Connecting to {} failed, retrying in {}s: {:#}",
+                addr, args.reconnect_interval_secs, e
+            ),
+            Ok(Attempt::NotFound) => warn!(
+                "Target device not found, retrying in {}s...",
+                args.reconnect_interval_secs
+            ),
+            Ok(Attempt::Stopped) => return None,
+        }
+        if sleep_or_stop(retry_interval, &mut stop).await {
+            return None;
+        }
+    }
+}
+
+// Written to the display just before disconnecting on shutdown
+const SHUTDOWN_MESSAGE: &str = "[disconnected]";
+
+// Parse the command line, filling in anything it leaves at the default from
+// --config
+fn parse_args() -> anyhow::Result<Args> {
+    let cli: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let matches = Args::command().get_matches_from(&cli);
+    let Some(path) = matches.get_one::<std::path::PathBuf>("config") else {
+        return Ok(Args::from_arg_matches(&matches)?);
+    };
+    let from_file = config::file_args(&Args::command(), path, &matches)?;
+    let mut argv = cli[..1].to_vec();
+    argv.extend(from_file);
+    argv.extend_from_slice(&cli[1..]);
+    Ok(Args::parse_from(argv))
+}
+
+// `plain_output`: stdout is a terminal and logs are plain text
+fn show_banner(mode: Banner, plain_output: bool) -> bool {
+    match mode {
+        Banner::Auto => plain_output,
+        Banner::Always => true,
+        Banner::Never => false,
+    }
+}
+
+fn print_banner(args: &Args, adapter: &str, bound: std::net::SocketAddr) {
+    let features = enabled_features(args);
+    println!("Vibe Keys {}", short_version());
+    println!("  adapter:   {}", adapter);
+    println!("  listening: http://{}", bound);
+    println!(
+        "  device:    service {} characteristic {}",
+        args.service_uuid, args.char_uuid
+    );
+    println!(
+        "  features:  {}",
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(", ")
+        }
+    );
+}
+
+// Optional behaviours switched on, by flag name
+fn enabled_features(args: &Args) -> Vec<&'static str> {
+    [
+        ("probe", args.probe),
+        ("require-char", args.require_char),
+        ("auto-mtu", args.auto_mtu),
+        ("verify-notify-uuid", args.verify_notify_uuid.is_some()),
+        ("verify-before-write", args.verify_before_write),
+        ("sequence-bytes", args.sequence_bytes.is_some()),
+        ("notify-uuid", args.notify_uuid.is_some()),
+        ("coalesce", args.coalesce),
+        ("collapse-repeats", args.collapse_repeats),
+        ("smooth-rate", args.smooth_rate.is_some()),
+        ("message-ttl-ms", args.message_ttl_ms.is_some()),
+        ("split-on", args.split_on.is_some()),
+        ("fallback-write-type", args.fallback_write_type),
+        ("restore-status-on-connect", args.restore_status_on_connect),
+        (
+            "supervision-timeout-ms",
+            args.supervision_timeout_ms.is_some(),
+        ),
+        ("health-log-secs", args.health_log_secs.is_some()),
+        ("no-ble", args.no_ble),
+        ("buffer-while-disconnected", args.buffer_while_disconnected),
+        ("auth-token", args.auth_token.is_some()),
+        ("cors-origin", !args.cors_origin.is_empty()),
+        ("rate-limit", args.rate_limit.is_some()),
+        (
+            "response-envelope",
+            args.response_envelope == ResponseEnvelope::Wrapped,
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(name, on)| on.then_some(name))
+    .collect()
+}
+
+// Resolves on Ctrl-C, tray Quit, or SIGTERM on Unix
+async fn shutdown_signal(quit_requested: &tokio::sync::Notify) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = term.recv() => Ok(()),
+            _ = quit_requested.notified() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = quit_requested.notified() => Ok(()),
+    }
+}
+
+async fn root() -> &'static str {
+    "BLE Controller Service\n"
+}
+
+// Liveness probe: answers as long as the HTTP server does, whatever the BLE
+// link is doing (see /ready and /status for that)
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+// Short version string, e.g. "0.1.0+1a2b3c4" when built from a git checkout
+fn short_version() -> String {
+    match option_env!("VIBEKEYS_GIT_HASH") {
+        Some(hash) => format!("{}+{}", env!("CARGO_PKG_VERSION"), hash),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+async fn version_handler() -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": option_env!("VIBEKEYS_GIT_HASH"),
+        "build_timestamp": option_env!("VIBEKEYS_BUILD_TIMESTAMP")
+            .and_then(|t| t.parse::<u64>().ok()),
+    }))
+}
+
+// Request and response shapes, generated from the serde types once
+async fn schema_handler() -> Json<serde_json::Value> {
+    static SCHEMA: std::sync::OnceLock<serde_json::Value> = std::sync::OnceLock::new();
+    let schema = SCHEMA.get_or_init(|| {
+        use schemars::schema_for;
+        serde_json::json!({
+            "requests": {
+                "POST /send": schema_for!(SendMessageRequest),
+                "POST /send/batch": schema_for!(SendBatchRequest),
+                "POST /send/raw": schema_for!(SendRawRequest),
+                "POST /status": schema_for!(StatusRequest),
+                "POST /status/raw": schema_for!(RawStatusRequest),
+                "POST /key": schema_for!(KeyRequest),
+                "POST /send/key": schema_for!(KeyRequest),
+                "POST /type": schema_for!(TypeRequest),
+                "POST /read/batch": schema_for!(ReadBatchRequest),
+                "POST /subscribe": schema_for!(SubscribeRequest),
+                "POST /unsubscribe": schema_for!(SubscribeRequest),
+            },
+            "responses": {
+                "GET /stats": schema_for!(StatsResponse),
+                "GET /write-config": schema_for!(WriteConfigResponse),
+                "GET /device": schema_for!(DeviceResponse),
+                "GET /events (text/event-stream)": schema_for!(Event),
+                "GET /events": schema_for!(Vec<Notification>),
+            },
+        })
+    });
+    Json(schema.clone())
+}
+
+// Tag every response with X-Vibekeys-Version
+async fn version_header(mut response: axum::response::Response) -> axum::response::Response {
+    if let Ok(value) = axum::http::HeaderValue::from_str(&short_version()) {
+        response.headers_mut().insert("x-vibekeys-version", value);
+    }
+    response
+}
+
+fn cors_layer(origins: &[String]) -> anyhow::Result<Option<tower_http::cors::CorsLayer>> {
+    use axum::http::{header, HeaderName, HeaderValue, Method};
+    use tower_http::cors::{AllowOrigin, CorsLayer};
+
+    if origins.is_empty() {
+        return Ok(None);
+    }
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        warn!("--cors-origin '*' lets any web page you visit call this API; use it only for local development");
+        AllowOrigin::any()
+    } else {
+        let values = origins
+            .iter()
+            .map(|o| {
+                HeaderValue::from_str(o.trim_end_matches('/'))
+                    .map_err(|_| anyhow::anyhow!("Invalid --cors-origin '{}'", o))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(values)
+    };
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                HeaderName::from_static("x-session"),
+                HeaderName::from_static("x-priority"),
+            ])
+            .expose_headers([HeaderName::from_static("x-vibekeys-version")]),
+    ))
+}
+
+// Reachable without --auth-token: the landing page, version and liveness
+// probe. Not /ready, whose ?probe=true writes to the device.
+const OPEN_PATHS: &[&str] = &["/", "/version", "/healthz"];
+
+async fn require_token(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let Some(token) = state.args.auth_token.as_deref() else {
+        return next.run(request).await;
+    };
+    if OPEN_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+    let presented = request
+        .headers()
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if tokens_match(presented.as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => ApiError::Unauthorized.into_response(),
+    }
+}
+
+// Routes that write to the device, for --rate-limit; GET /send writes too
+const WRITE_PATHS: &[&str] = &[
+    "/send",
+    "/send/batch",
+    "/send/raw",
+    "/status",
+    "/status/raw",
+    "/key",
+    "/send/key",
+    "/type",
+];
+
+async fn rate_limit(
+    State(state): State<AppState>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> Response {
+    let Some(limiter) = &state.rate_limiter else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path();
+    let writes = WRITE_PATHS.contains(&path)
+        && (request.method() == axum::http::Method::POST || path == "/send");
+    if !writes {
+        return next.run(request).await;
+    }
+    match limiter.acquire(std::time::Instant::now()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => ApiError::RateLimited {
+            retry_after_secs: wait.as_secs_f64().ceil().max(1.0) as u64,
+        }
+        .into_response(),
+    }
+}
+
+// Compares every byte regardless of where the first mismatch is, so timing
+// doesn't reveal how much of a guess was right
+fn tokens_match(presented: &[u8], expected: &[u8]) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Applied to every route so handlers keep returning their flat bodies. Only
+// successful JSON is wrapped; SSE streams and error bodies pass through.
+async fn response_envelope(State(state): State<AppState>, response: Response) -> Response {
+    let is_json = response
+        .headers()
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if state.args.response_envelope == ResponseEnvelope::Flat
+        || !response.status().is_success()
+        || !is_json
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return ApiError::Internal(format!("Failed to read response: {}", e)).into_response()
+        }
+    };
+    let Ok(data) = serde_json::from_slice::<serde_json::Value>(&bytes) else {
+        return Response::from_parts(parts, axum::body::Body::from(bytes));
+    };
+    parts.headers.remove(axum::http::header::CONTENT_LENGTH);
+    let wrapped = serde_json::json!({ "ok": true, "data": data });
+    Response::from_parts(parts, axum::body::Body::from(wrapped.to_string()))
+}
+
+// Ready when a device is held and reports connected. With ?probe=true also
+// write --ready-probe-hex, since a stale link can still report connected.
+// Probing is opt-in so frequent readiness polls don't wear the device.
+async fn ready_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ReadyQuery>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let p = state
+        .peripheral
+        .lock()
+        .await
+        .clone()
+        .ok_or_else(|| ApiError::Unavailable("No device".to_string()))?;
+    if !p.is_connected().await.unwrap_or(false) {
+        return Err(ApiError::Unavailable(format!(
+            "{} is not connected",
+            p.address()
+        )));
+    }
+    if query.probe {
+        let _order = state.write_order.lock().await;
+        let _op = state.ble_op().await;
+        let probe = send_message(
+            &p,
+            state.display_target(p.address()),
+            &state.args.ready_probe_hex.0,
+            state.write_options(WriteType::WithResponse),
+        );
+        let limit = Duration::from_millis(state.args.ready_probe_timeout_ms);
+        match time::timeout(limit, probe).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => return Err(ApiError::Unavailable(format!("Probe write failed: {}", e))),
+            Err(_) => {
+                return Err(ApiError::Unavailable(format!(
+                    "Probe write timed out after {:?}",
+                    limit
+                )))
+            }
+        }
+    }
+    Ok(Json(serde_json::json!({
+        "status": "ready",
+        "address": p.address().to_string(),
+        "probed": query.probe,
+    })))
+}
+
+async fn send_message_handler(
+    State(state): State<AppState>,
+    Query(query): Query<SendQuery>,
+    headers: HeaderMap,
+) -> Result<SendResponse, ApiError> {
+    let priority = header_priority(&headers);
+    let write_type = state.args.write_type.into();
+    let result = send_to_peripheral(
+        &state,
+        "Hello from HTTP GET!",
+        query.sync,
+        priority,
+        write_type,
+    )
+    .await;
+    if result.is_ok() {
+        record_writer(&state, &headers);
+        forget_last_status(&state);
+    }
+    result
+}
+
+async fn send_message_post(
+    State(state): State<AppState>,
+    Query(query): Query<SendQuery>,
+    headers: HeaderMap,
+    Json(req): Json<SendMessageRequest>,
+) -> Result<SendResponse, ApiError> {
+    let priority = header_priority(&headers);
+    let write_type = req.write_type.unwrap_or(state.args.write_type).into();
+    let result = send_to_peripheral(&state, &req.message, query.sync, priority, write_type).await;
+    if result.is_ok() {
+        record_writer(&state, &headers);
+        forget_last_status(&state);
+    }
+    result
+}
+
+// Attribute a write to the X-Session token from POST /session, if any; only
+// called once the write has been queued or performed
+fn record_writer(state: &AppState, headers: &HeaderMap) {
+    let token = headers.get("x-session").and_then(|v| v.to_str().ok());
+    if !state.sessions.record_write(token) {
+        warn!("Write with unknown session token, not attributed");
+    }
+}
+
+async fn create_session_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "session": state.sessions.create() }))
+}
+
+// Known sessions and which one wrote last
+async fn sessions_handler(State(state): State<AppState>) -> Json<sessions::SessionReport> {
+    Json(state.sessions.report())
+}
+
+fn header_priority(headers: &HeaderMap) -> Priority {
+    Priority::from_header(headers.get("x-priority").and_then(|v| v.to_str().ok()))
+}
+
+// The buffer to hold a write in instead of failing it: only while the link
+// is down for a reason the monitor will recover from
+fn offline_buffer(state: &AppState) -> Option<&OfflineBuffer> {
+    let offline = state.offline.as_deref()?;
+    let down = *state.phase.lock().unwrap() != LinkPhase::Connected;
+    (down && !state.user_disconnected.load(Ordering::Relaxed)).then_some(offline)
+}
+
+// Response to a write: 200, or 202 when --buffer-while-disconnected held it
+type SendResponse = (StatusCode, Json<serde_json::Value>);
+
+// How a message was taken for writing
+enum Accepted {
+    // Held until a device is connected again; the buffer now has this many
+    Buffered(usize),
+    // Queued, one receiver per part
+    Queued(Vec<tokio::sync::oneshot::Receiver<WriteResult>>),
+}
+
+// Buffer `parts` as one message while the link is down, or else queue them
+// together. The caller holds write_order.
+async fn accept_write(
+    state: &AppState,
+    parts: &[&[u8]],
+    write_type: WriteType,
+    priority: Priority,
+) -> Result<Accepted, ApiError> {
+    if let Some(offline) = offline_buffer(state) {
+        let (buffered, dropped) = offline.push(BufferedWrite {
+            parts: parts.iter().map(|part| part.to_vec()).collect(),
+            write_type,
+        });
+        if dropped {
+            warn!("Disconnected buffer full, dropped the oldest message");
+        }
+        return Ok(Accepted::Buffered(buffered));
+    }
+    ensure_writable(state).await?;
+    let group = state.write_queue.next_group();
+    let pending = parts
+        .iter()
+        .map(|part| enqueue_write(state, part, write_type, priority, group))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Accepted::Queued(pending))
+}
+
+// Queue `message`. With `sync` wait until it has been written and report
+// the outcome; otherwise respond as soon as it is queued.
+async fn send_to_peripheral(
+    state: &AppState,
+    message: &str,
+    sync: bool,
+    priority: Priority,
+    write_type: WriteType,
+) -> Result<SendResponse, ApiError> {
+    // Counted in bytes, as the device limits are
+    let limit = state.args.max_message_bytes;
+    if message.len() > limit {
+        return Err(ApiError::MessageTooLarge {
+            size: message.len(),
+            limit,
+        });
+    }
+    // A zero-length write behaves differently from device to device
+    let data = match (message.is_empty(), state.args.empty_message) {
+        (false, _) => message.as_bytes(),
+        (true, EmptyMessage::Reject) => {
+            return Err(ApiError::BadRequest(
+                "Message must not be empty".to_string(),
+            ))
+        }
+        (true, EmptyMessage::Noop) => {
+            return Ok((
+                StatusCode::OK,
+                Json(serde_json::json!({ "status": "ok", "message": message, "written": false })),
+            ))
+        }
+        (true, EmptyMessage::Clear) => &state.args.clear_hex.0,
+    };
+    // With --split-on newline each non-empty line is its own write
+    let mut parts: Vec<&[u8]> = match state.args.split_on {
+        Some(SplitOn::Newline) => message
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::as_bytes)
+            .collect(),
+        None => Vec::new(),
+    };
+    if parts.is_empty() {
+        parts.push(data);
+    }
+
+    let pending = {
+        // Keep the parts together even if another sequence is being written
+        let _order = state.write_order.lock().await;
+        let now = std::time::Instant::now();
+        if let Some(repeats) = &state.repeats {
+            if repeats.lock().unwrap().is_repeat(data, now) {
+                return Ok((
+                    StatusCode::OK,
+                    Json(serde_json::json!({
+                        "status": "ok",
+                        "message": message,
+                        "written": false,
+                        "collapsed": true,
+                    })),
+                ));
+            }
+        }
+        let accepted = accept_write(state, &parts, write_type, priority).await?;
+        // Only now, so a message that failed to queue isn't taken for a
+        // repeat when it's retried
+        record_last_message(state, data, now);
+        match accepted {
+            Accepted::Buffered(buffered) => {
+                return Ok((
+                    StatusCode::ACCEPTED,
+                    Json(serde_json::json!({
+                        "status": "buffered",
+                        "message": message,
+                        "buffered": buffered,
+                    })),
+                ))
+            }
+            Accepted::Queued(pending) => pending,
+        }
+    };
+    let mut body = serde_json::json!({ "status": "queued", "message": message });
+    if state.args.split_on.is_some() {
+        body["lines"] = parts.len().into();
+    }
+    if state.args.no_ble {
+        body["simulated"] = true.into();
+    }
+    if !sync {
+        return Ok((StatusCode::OK, Json(body)));
+    }
+
+    let mut verified = None;
+    for (i, done) in pending.into_iter().enumerate() {
+        let result = await_write(done).await;
+        if result.is_err() {
+            // Let the same message be retried straight away
+            forget_last_message(state);
+        }
+        let echoed = match parts.len() {
+            1 => result?,
+            _ => result.map_err(|e| e.context(&format!("line {}", i)))?,
+        };
+        if let Some(echoed) = echoed {
+            verified = Some(verified.unwrap_or(true) && echoed);
+        }
+    }
+    body["status"] = "ok".into();
+    if let Some(verified) = verified {
+        body["verified"] = verified.into();
+    }
+    Ok((StatusCode::OK, Json(body)))
+}
+
+// Write bytes that needn't be UTF-8, e.g. firmware control codes. Chunked
+// and queued like /send, but never split into lines or collapsed.
+async fn send_raw_handler(
+    State(state): State<AppState>,
+    Query(query): Query<SendQuery>,
+    headers: HeaderMap,
+    Json(req): Json<SendRawRequest>,
+) -> Result<SendResponse, ApiError> {
+    use base64::Engine as _;
+
+    let data = match (&req.hex, &req.base64) {
+        (Some(hex), None) => keys::parse_hex(hex).map_err(ApiError::BadRequest)?,
+        (None, Some(encoded)) => base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| ApiError::BadRequest(format!("Invalid base64: {}", e)))?,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "Give exactly one of hex or base64".to_string(),
+            ))
+        }
+    };
+    if data.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Payload must not be empty".to_string(),
+        ));
+    }
+    let limit = state.args.max_message_bytes;
+    if data.len() > limit {
+        return Err(ApiError::MessageTooLarge {
+            size: data.len(),
+            limit,
+        });
+    }
+
+    let write_type = req.write_type.unwrap_or(state.args.write_type).into();
+    let accepted = {
+        let _order = state.write_order.lock().await;
+        accept_write(&state, &[&data], write_type, header_priority(&headers)).await?
+    };
+    record_writer(&state, &headers);
+    // The display no longer shows the last /send message or status
+    forget_display(&state);
+    let mut body = serde_json::json!({
+        "status": "queued",
+        "bytes": data.len(),
+        "hex": hex_string(&data),
+    });
+    let done = match accepted {
+        Accepted::Buffered(buffered) => {
+            body["status"] = "buffered".into();
+            body["buffered"] = buffered.into();
+            return Ok((StatusCode::ACCEPTED, Json(body)));
+        }
+        Accepted::Queued(mut pending) => pending.remove(0),
+    };
+    if state.args.no_ble {
+        body["simulated"] = true.into();
+    }
+    if !query.sync {
+        return Ok((StatusCode::OK, Json(body)));
+    }
+    if let Some(verified) = await_write(done).await? {
+        body["verified"] = verified.into();
+    }
+    body["status"] = "ok".into();
+    Ok((StatusCode::OK, Json(body)))
+}
+
+fn record_last_message(state: &AppState, data: &[u8], now: std::time::Instant) {
+    if let Some(repeats) = &state.repeats {
+        repeats.lock().unwrap().record(data, now);
+    }
+}
+
+fn forget_last_message(state: &AppState) {
+    if let Some(repeats) = &state.repeats {
+        repeats.lock().unwrap().forget();
+    }
+}
+
+// Something else is on the display, so --status-on-change must write the
+// next status even if it matches the last one
+fn forget_last_status(state: &AppState) {
+    state.status_writes.lock().unwrap().forget();
+}
+
+// Neither the last message nor the last status is on the display any more
+fn forget_display(state: &AppState) {
+    forget_last_message(state);
+    forget_last_status(state);
+}
+
+// Write a batch of messages in order, each with its own write type
+async fn send_batch_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<SendBatchRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let parts = batch_parts(&req.messages, state.args.write_type);
+    write_sequence_to_peripheral(&state, &parts, Duration::ZERO).await?;
+    record_writer(&state, &headers);
+    forget_display(&state);
+    Ok(Json(
+        serde_json::json!({ "status": "ok", "count": parts.len() }),
+    ))
+}
+
+// Each message with its own write type, else `default`
+fn batch_parts(messages: &[BatchMessage], default: WriteKind) -> Vec<(&[u8], WriteType)> {
+    messages
+        .iter()
+        .map(|m| {
+            let kind = m.write_type.unwrap_or(default);
+            (m.message.as_bytes(), kind.into())
+        })
+        .collect()
+}
+
+// Write each part in order, pausing `delay` between parts
+async fn write_sequence_to_peripheral(
+    state: &AppState,
+    parts: &[(&[u8], WriteType)],
+    delay: Duration,
+) -> Result<(), ApiError> {
+    let _order = state.write_order.lock().await;
+    // One message as far as --coalesce is concerned
+    let group = state.write_queue.next_group();
+    for (i, (part, write_type)) in parts.iter().enumerate() {
+        if i > 0 && !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+        write_to_peripheral(state, part, *write_type, group)
+            .await
+            .map_err(|e| e.context(&format!("part {}", i)))?;
+    }
+    Ok(())
+}
+
+// Queue a write and wait for the writer task to perform it
+async fn write_to_peripheral(
+    state: &AppState,
+    data: &[u8],
+    write_type: WriteType,
+    group: u64,
+) -> WriteResult {
+    ensure_writable(state).await?;
+    await_write(enqueue_write(
+        state,
+        data,
+        write_type,
+        Priority::Normal,
+        group,
+    )?)
+    .await
+}
+
+// Fail a write up front, rather than after reporting it queued, when there
+// is no device to take it or the device lacks the display characteristic
+async fn ensure_writable(state: &AppState) -> Result<(), ApiError> {
+    if state.args.no_ble {
+        return Ok(());
+    }
+    let p = connected_peripheral(state).await?;
+    if state.args.verify_before_write && !p.is_connected().await.unwrap_or(false) {
+        link_lost(state);
+        return Err(ApiError::NotConnected(format!(
+            "{} is not connected",
+            p.address()
+        )));
+    }
+    let target = state.display_target(p.address());
+    match resolve_characteristic(&p.services(), target) {
+        Some(_) => Ok(()),
+        None => Err(ApiError::NotFound(
+            MissingCharacteristic(target.characteristic).to_string(),
+        )),
+    }
+}
+
+// Queue a write; the receiver resolves once the writer task has performed it.
+// `group` is shared by the parts of one message (see WriteQueue::next_group).
+fn enqueue_write(
+    state: &AppState,
+    data: &[u8],
+    write_type: WriteType,
+    priority: Priority,
+    group: u64,
+) -> Result<tokio::sync::oneshot::Receiver<WriteResult>, ApiError> {
+    let (done, result) = tokio::sync::oneshot::channel();
+    if state.args.no_ble {
+        info!(
+            "Simulated write of {} bytes: {}",
+            data.len(),
+            String::from_utf8_lossy(data)
+        );
+        state.events.publish(Event::Sent {
+            message: String::from_utf8_lossy(data).into_owned(),
+            bytes: data.len(),
+        });
+        done.send(Ok(None)).ok();
+        return Ok(result);
+    }
+    let job = WriteJob {
+        data: data.to_vec(),
+        write_type,
+        priority,
+        group,
+        enqueued_at: std::time::Instant::now(),
+        done,
+    };
+    if state.write_queue.push(job).is_err() {
+        state.stats.rejected();
+        return Err(ApiError::Unavailable(format!(
+            "Write queue is full ({} messages waiting)",
+            state.write_queue.capacity()
+        )));
+    }
+    Ok(result)
+}
+
+async fn await_write(result: tokio::sync::oneshot::Receiver<WriteResult>) -> WriteResult {
+    result
+        .await
+        .unwrap_or_else(|_| Err(ApiError::Internal("Write queue closed".to_string())))
+}
+
+// Writer task: take queued writes in order and run up to
+// --max-inflight-writes of them at once
+async fn writer_task(state: AppState) {
+    let ttl = state.args.message_ttl_ms.map(Duration::from_millis);
+    let gap = state
+        .args
+        .smooth_rate
+        .map(|rate| Duration::from_secs(1) / rate);
+    let mut next_write = time::Instant::now();
+    loop {
+        // Wait for a job before taking a slot, so an idle writer holds none
+        // and shutdown can tell when every write has finished
+        let mut job = state.write_queue.pop().await;
+        let Ok(slot) = state.write_slots.clone().acquire_owned().await else {
+            return;
+        };
+        time::sleep_until(next_write).await;
+        if state.args.coalesce {
+            // Supersede whole messages only, so every part of the newest
+            // one is still written
+            while let Some(newest) = state.write_queue.newest_group().filter(|&g| g > job.group) {
+                for old in std::iter::once(job).chain(state.write_queue.take_before(newest)) {
+                    state.stats.coalesced();
+                    old.done
+                        .send(Err(ApiError::Unavailable(
+                            "Superseded by a newer message".to_string(),
+                        )))
+                        .ok();
+                }
+                job = state.write_queue.pop().await;
+            }
+        }
+        if let Some(gap) = gap {
+            next_write = time::Instant::now() + gap;
+        }
+        if job.is_expired(ttl, std::time::Instant::now()) {
+            state.stats.expired();
+            let waited = job.enqueued_at.elapsed();
+            warn!("Dropping message that waited {:?} in the queue", waited);
+            job.done
+                .send(Err(ApiError::Unavailable(format!(
+                    "Message expired after waiting {:?}",
+                    waited
+                ))))
+                .ok();
+            continue;
+        }
+        let state = state.clone();
+        tokio::spawn(async move {
+            let result = write_now(&state, &job.data, job.write_type).await;
+            drop(slot);
+            match &result {
+                Ok(_) => {
+                    state.stats.write_ok();
+                    state.events.publish(Event::Sent {
+                        message: String::from_utf8_lossy(&job.data).into_owned(),
+                        bytes: job.data.len(),
+                    });
+                }
+                Err(e) => {
+                    state.stats.write_failed();
+                    state.events.publish(Event::Error {
+                        code: e.code(),
+                        message: e.to_string(),
+                    });
+                }
+            }
+            job.done.send(result).ok();
+        });
+    }
+}
+
+// Returns whether the device echoed the write when --verify-notify-uuid is set
+async fn write_now(state: &AppState, data: &[u8], write_type: WriteType) -> WriteResult {
+    // Don't hold the peripheral lock across the write; the write slots bound
+    // how many writes may be outstanding instead.
+    let p = connected_peripheral(state).await?;
+    if state.args.verify_before_write && !p.is_connected().await.unwrap_or(false) {
+        link_lost(state);
+        return Err(ApiError::NotConnected(format!(
+            "{} is not connected",
+            p.address()
+        )));
+    }
+    // Listen before writing so a fast echo isn't missed
+    let echoes = match state.args.verify_notify_uuid {
+        Some(uuid) => Some((
+            uuid,
+            p.notifications()
+                .await
+                .map_err(|e| ApiError::from_ble(&e.into()))?,
+        )),
+        None => None,
+    };
+    let framed = state.sequence.as_ref().map(|sequence| sequence.frame(data));
+    let payload = framed
+        .as_ref()
+        .map_or(data, |(_, framed)| framed.as_slice());
+    let options = state.write_options(write_type);
+    let chunked = options.chunk_size.is_some_and(|size| payload.len() > size);
+    let chunk_order = if chunked {
+        (Some(state.chunk_order.write().await), None)
+    } else {
+        (None, Some(state.chunk_order.read().await))
+    };
+    state.supervision.attempt(std::time::Instant::now());
+    let op = state.ble_op().await;
+    if let Err(e) = send_message(&p, state.display_target(p.address()), payload, options).await {
+        let err = ApiError::from_ble(&e);
+        // The link is fine if the characteristic is absent or the write was
+        // refused for its size
+        match err {
+            ApiError::NotFound(_) | ApiError::PayloadTooLarge(_) => state.supervision.success(),
+            ApiError::NotConnected(_) => link_lost(state),
+            _ => {
+                p.disconnect().await.ok();
+            }
+        }
+        return Err(err);
+    }
+    drop(op);
+    drop(chunk_order);
+    state.supervision.success();
+    state
+        .last_write_ok
+        .lock()
+        .unwrap()
+        .insert(p.address(), std::time::Instant::now());
+    match echoes {
+        Some((uuid, stream)) => {
+            let timeout = Duration::from_millis(state.args.verify_timeout_ms);
+            let verified = match &framed {
+                Some((seq, _)) => await_sequence(stream, uuid, seq, timeout).await,
+                None => await_echo(stream, uuid, data, timeout).await,
+            };
+            if !verified {
+                warn!("Write was not echoed back on {} within {:?}", uuid, timeout);
+            }
+            Ok(Some(verified))
+        }
+        None => Ok(None),
+    }
+}
+
+// The device reported NotConnected: believe it over is_connected, and have
+// the monitor drop the device and reconnect straight away
+fn link_lost(state: &AppState) {
+    *state.phase.lock().unwrap() = LinkPhase::Disconnected;
+    state.link_check.notify_one();
+}
+
+// Collect notifications from `uuid` until `expected.len()` bytes have been
+// echoed (possibly across several chunks) or the timeout passes
+async fn await_echo(
+    mut stream: impl futures::Stream<Item = ValueNotification> + Unpin,
+    uuid: Uuid,
+    expected: &[u8],
+    timeout: Duration,
+) -> bool {
+    let mut echoed = Vec::with_capacity(expected.len());
+    let collect = async {
+        while let Some(notification) = stream.next().await {
+            if notification.uuid == uuid {
+                echoed.extend_from_slice(&notification.value);
+                if echoed.len() >= expected.len() {
+                    break;
+                }
+            }
+        }
+    };
+    time::timeout(timeout, collect).await.is_ok() && echoed == expected
+}
+
+// Wait for a notification from `uuid` confirming sequence number `seq`;
+// confirmations of other writes are skipped
+async fn await_sequence(
+    mut stream: impl futures::Stream<Item = ValueNotification> + Unpin,
+    uuid: Uuid,
+    seq: &[u8],
+    timeout: Duration,
+) -> bool {
+    let confirm = async {
+        while let Some(notification) = stream.next().await {
+            if notification.uuid == uuid && sequence::confirms(seq, &notification.value) {
+                return true;
+            }
+        }
+        false
+    };
+    time::timeout(timeout, confirm).await.unwrap_or(false)
+}
+
+// Clone of the current peripheral, or the configured no-device error
+async fn connected_peripheral(state: &AppState) -> Result<PlatformPeripheral, ApiError> {
+    state
+        .peripheral
+        .lock()
+        .await
+        .clone()
+        .ok_or(ApiError::NoDevice(state.args.no_device_status.code()))
+}
+
+async fn status_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<StatusRequest>,
+) -> Result<SendResponse, ApiError> {
+    // Remembered even if the write fails, so a reconnect can restore it
+    *state.last_status.lock().unwrap() = Some(req.status.message().to_string());
+    let result = write_status(&state, req.status.message()).await;
+    if result.is_ok() {
+        record_writer(&state, &headers);
+    }
+    result
+}
+
+// Write a status message, unless --status-on-change finds it unchanged
+async fn write_status(state: &AppState, message: &str) -> Result<SendResponse, ApiError> {
+    {
+        let now = std::time::Instant::now();
+        let mut status_writes = state.status_writes.lock().unwrap();
+        if state.args.status_on_change && status_writes.is_repeat(message.as_bytes(), now) {
+            return Ok((
+                StatusCode::OK,
+                Json(serde_json::json!({ "status": "ok", "message": message, "written": false })),
+            ));
+        }
+        status_writes.record(message.as_bytes(), now);
+    }
+    let write_type = state.args.write_type.into();
+    let result = send_to_peripheral(state, message, true, Priority::Normal, write_type).await;
+    if result.is_err() {
+        // Not on the display, so the same status must not count as unchanged
+        state.status_writes.lock().unwrap().forget();
+    }
+    result
+}
+
+// Rewrite the last status once --status-keepalive-secs pass without one
+async fn status_keepalive_task(state: AppState) {
+    let mut tick = time::interval(Duration::from_secs(1));
+    loop {
+        tick.tick().await;
+        let due = state
+            .status_writes
+            .lock()
+            .unwrap()
+            .due(std::time::Instant::now());
+        let Some(message) = due else {
+            continue;
+        };
+        if state.peripheral.lock().await.is_none() {
+            continue;
+        }
+        debug!("Rewriting status for keep-alive");
+        let group = state.write_queue.next_group();
+        if let Err(e) =
+            write_to_peripheral(&state, &message, state.args.write_type.into(), group).await
+        {
+            warn!("Status keep-alive write failed: {}", e);
+        }
+    }
+}
+
+// Connection state without writing anything. Always 200 so monitoring can
+// poll it; `connected` says whether a device is linked.
+async fn connection_status_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let phase = *state.phase.lock().unwrap();
+    let Some(p) = state.peripheral.lock().await.clone() else {
+        return Json(serde_json::json!({ "connected": false, "phase": phase }));
+    };
+    let connected = phase == LinkPhase::Connected && p.is_connected().await.unwrap_or(false);
+    let props = p.properties().await.ok().flatten();
+    Json(serde_json::json!({
+        "connected": connected,
+        "phase": phase,
+        "device_name": props.as_ref().and_then(|props| props.local_name.clone()),
+        "address": p.address().to_string(),
+        "rssi": props.and_then(|props| props.rssi),
+    }))
+}
+
+// Write a status outside the Status enum, with the same [..] framing
+async fn raw_status_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<RawStatusRequest>,
+) -> Result<SendResponse, ApiError> {
+    let status = req.status.trim();
+    if status.is_empty() || status.chars().count() > MAX_RAW_STATUS_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "Status must be 1 to {} characters",
+            MAX_RAW_STATUS_LEN
+        )));
+    }
+    if status
+        .chars()
+        .any(|c| c.is_control() || c == '[' || c == ']')
+    {
+        return Err(ApiError::BadRequest(
+            "Status must not contain control characters or brackets".to_string(),
+        ));
+    }
+    let allow = &state.args.raw_status_allow;
+    if !allow.is_empty() && !allow.iter().any(|a| a == status) {
+        return Err(ApiError::BadRequest(format!(
+            "Status '{}' is not allowed; allowed statuses: {}",
+            status,
+            allow.join(", ")
+        )));
+    }
+    let framed = frame_status(status);
+    // Remembered even if the write fails, so a reconnect can restore it
+    *state.last_status.lock().unwrap() = Some(framed.clone());
+    let result = write_status(&state, &framed).await;
+    if result.is_ok() {
+        record_writer(&state, &headers);
+    }
+    result
+}
+
+async fn key_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<KeyRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bytes = match &req.key {
+        KeyId::Name(name) => state
+            .keys
+            .get(name)
+            .ok_or_else(|| unknown_key(&state, name))?,
+        KeyId::Code(code) => vec![*code],
+    };
+    press_key(&state, &headers, req, bytes).await
+}
+
+// Like /key, but a name must be in the key table (see --key-map); single
+// characters aren't taken as themselves
+async fn send_key_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<KeyRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bytes = match &req.key {
+        KeyId::Name(name) => state
+            .keys
+            .named(name)
+            .ok_or_else(|| unknown_key(&state, name))?,
+        KeyId::Code(code) => vec![*code],
+    };
+    press_key(&state, &headers, req, bytes).await
+}
+
+fn unknown_key(state: &AppState, name: &str) -> ApiError {
+    ApiError::UnknownKey {
+        key: name.to_string(),
+        supported: state.keys.names().into_iter().map(String::from).collect(),
+    }
+}
+
+// Write the key's bytes with its modifiers applied
+async fn press_key(
+    state: &AppState,
+    headers: &HeaderMap,
+    req: KeyRequest,
+    bytes: Vec<u8>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let bytes = state
+        .keys
+        .apply_modifiers(bytes, &req.modifiers)
+        .map_err(ApiError::BadRequest)?;
+    let group = state.write_queue.next_group();
+    write_to_peripheral(state, &bytes, state.args.write_type.into(), group).await?;
+    record_writer(state, headers);
+    forget_display(state);
+    Ok(Json(serde_json::json!({
+        "status": "ok",
+        "key": req.key,
+        "modifiers": req.modifiers,
+    })))
+}
+
+// Type text one user-perceived character (grapheme cluster) per write
+async fn type_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<TypeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let write_type = state.args.write_type.into();
+    let parts: Vec<(&[u8], WriteType)> = req
+        .text
+        .graphemes(true)
+        .map(|g| (g.as_bytes(), write_type))
+        .collect();
+    write_sequence_to_peripheral(&state, &parts, state.key_delay).await?;
+    record_writer(&state, &headers);
+    forget_display(&state);
+    Ok(Json(serde_json::json!({
+        "status": "ok",
+        "text": req.text,
+        "writes": parts.len(),
+    })))
+}
+
+#[derive(Serialize, JsonSchema)]
+struct StatsResponse {
+    #[serde(flatten)]
+    counters: stats::StatsSnapshot,
+    queue_depth: usize,
+    queue_high_water: usize,
+    queue_capacity: usize,
+}
+
+async fn stats_handler(State(state): State<AppState>) -> Json<StatsResponse> {
+    Json(StatsResponse {
+        counters: state.stats.snapshot(),
+        queue_depth: state.write_queue.depth(),
+        queue_high_water: state.write_queue.high_water(),
+        queue_capacity: state.write_queue.capacity(),
+    })
+}
+
+// How messages are turned into characteristic writes
+#[derive(Serialize, JsonSchema)]
+struct WriteConfigResponse {
+    /// Bytes per write in effect, after any --auto-mtu probe; null when
+    /// messages are written unchunked
+    chunk_size: Option<usize>,
+    /// --chunk-size as given
+    configured_chunk_size: u16,
+    auto_mtu: bool,
+    chunk_delay_ms: u64,
+    write_type: WriteKind,
+    fallback_write_type: bool,
+    /// Messages are written as their UTF-8 bytes
+    encoding: &'static str,
+    /// Lines written separately (--split-on)
+    split_on: Option<&'static str>,
+    /// Width of the sequence-number prefix on every write (--sequence-bytes)
+    sequence_bytes: Option<u8>,
+    /// Around every /status and /status/raw message
+    status_prefix: &'static str,
+    status_suffix: &'static str,
+}
+
+async fn write_config_handler(State(state): State<AppState>) -> Json<WriteConfigResponse> {
+    let args = &state.args;
+    Json(WriteConfigResponse {
+        chunk_size: state.chunk_size(),
+        configured_chunk_size: args.chunk_size,
+        auto_mtu: args.auto_mtu,
+        chunk_delay_ms: args.chunk_delay_ms,
+        write_type: args.write_type,
+        fallback_write_type: args.fallback_write_type,
+        encoding: "utf-8",
+        split_on: args.split_on.map(|SplitOn::Newline| "newline"),
+        sequence_bytes: args.sequence_bytes,
+        status_prefix: "[",
+        status_suffix: "]",
+    })
+}
+
+// With `Accept: text/event-stream`, server-sent events for every send,
+// connection change, notification and write error. Otherwise drain the
+// buffered --notify-uuid notifications as a JSON array.
+async fn events_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let wants_stream = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/event-stream"));
+    if !wants_stream {
+        return Json(state.notifications.drain()).into_response();
+    }
+
+    // The guard travels with the stream, so the client stays listed in
+    // /subscribers until axum drops the stream on disconnect
+    let guard = state.subscribers.register("events");
+    let stream = futures::stream::unfold(
+        (state.events.subscribe(), guard),
+        |(mut rx, guard)| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        let sse = SseEvent::default().event(event.name()).json_data(&event);
+                        return Some((sse, (rx, guard)));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Event subscriber lagged, skipped {} events", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+async fn subscribers_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    // --notify-uuid plus whatever POST /subscribe added
+    let mut characteristics = state.subscriptions.lock().unwrap().clone();
+    characteristics.extend(state.args.notify_uuid);
+    let characteristics: Vec<Uuid> = characteristics.into_iter().collect();
+    let subscribers = state.subscribers.list(&characteristics);
+    Json(serde_json::json!({
+        "count": subscribers.len(),
+        "subscribers": subscribers,
+    }))
+}
+
+async fn rssi_history_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let history = &state.rssi_history;
+    Json(serde_json::json!({
+        "samples": history.samples(),
+        "trend": history.trend(),
+    }))
+}
+
+// Connected peripheral and the notifiable characteristic `uuid` on it
+async fn notify_characteristic(
+    state: &AppState,
+    uuid: Uuid,
+) -> Result<(PlatformPeripheral, Characteristic), ApiError> {
+    let p = connected_peripheral(state).await?;
+    let c = p
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == uuid)
+        .ok_or_else(|| ApiError::BadRequest(format!("Characteristic {} not found", uuid)))?;
+    if !c
+        .properties
+        .intersects(CharPropFlags::NOTIFY | CharPropFlags::INDICATE)
+    {
+        return Err(ApiError::BadRequest(format!(
+            "Characteristic {} does not support notifications or indications",
+            uuid
+        )));
+    }
+    Ok((p, c))
+}
+
+async fn subscribe_handler(
+    State(state): State<AppState>,
+    Json(req): Json<SubscribeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (p, c) = notify_characteristic(&state, req.characteristic).await?;
+    let op = state.ble_op().await;
+    p.subscribe(&c)
+        .await
+        .map_err(|e| ApiError::from_ble(&e.into()))?;
+    drop(op);
+    let subscriptions = {
+        let mut subscriptions = state.subscriptions.lock().unwrap();
+        subscriptions.insert(c.uuid);
+        subscriptions.clone()
+    };
+    // A fresh consumer, as none may be running for this device yet
+    start_notify_consumer(&state, &p)
+        .await
+        .map_err(|e| ApiError::from_ble(&e))?;
+    info!("Subscribed to {}", c.uuid);
+    Ok(Json(serde_json::json!({
+        "status": "subscribed",
+        "subscriptions": subscriptions,
+    })))
+}
+
+async fn unsubscribe_handler(
+    State(state): State<AppState>,
+    Json(req): Json<SubscribeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (p, c) = notify_characteristic(&state, req.characteristic).await?;
+    if !state.subscriptions.lock().unwrap().contains(&c.uuid) {
+        return Err(ApiError::Conflict(format!("Not subscribed to {}", c.uuid)));
+    }
+    let op = state.ble_op().await;
+    p.unsubscribe(&c)
+        .await
+        .map_err(|e| ApiError::from_ble(&e.into()))?;
+    drop(op);
+    let mut subscriptions = state.subscriptions.lock().unwrap();
+    subscriptions.remove(&c.uuid);
+    info!("Unsubscribed from {}", c.uuid);
+    Ok(Json(serde_json::json!({
+        "status": "unsubscribed",
+        "subscriptions": *subscriptions,
+    })))
+}
+
+// Read several characteristics in one call; a characteristic that is absent
+// or fails to read is reported in its own entry instead of failing the batch
+async fn read_batch_handler(
+    State(state): State<AppState>,
+    Json(req): Json<ReadBatchRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let p = connected_peripheral(&state).await?;
+    let characteristics = p.characteristics();
+
+    // Same error shape as a failed request, per characteristic
+    let failed = |uuid: Uuid, e: ApiError| serde_json::json!({ "uuid": uuid, "error": e.code(), "message": e.to_string() });
+    let mut results = Vec::with_capacity(req.characteristics.len());
+    for uuid in req.characteristics {
+        let entry = match characteristics.iter().find(|c| c.uuid == uuid) {
+            None => failed(
+                uuid,
+                ApiError::NotFound(MissingCharacteristic(uuid).to_string()),
+            ),
+            Some(c) if !c.properties.contains(CharPropFlags::READ) => failed(
+                uuid,
+                ApiError::Unsupported(format!("Characteristic {} is not readable", uuid)),
+            ),
+            Some(c) => {
+                state.supervision.attempt(std::time::Instant::now());
+                let read = {
+                    let _op = state.ble_op().await;
+                    p.read(c).await
+                };
+                match read {
+                    Ok(value) => {
+                        state.supervision.success();
+                        serde_json::json!({
+                            "uuid": uuid,
+                            "value": String::from_utf8_lossy(&value),
+                            "hex": hex_string(&value),
+                        })
+                    }
+                    Err(e) => failed(uuid, ApiError::from_ble(&e.into())),
+                }
+            }
+        };
+        results.push(entry);
+    }
+    Ok(Json(
+        serde_json::json!({ "status": "ok", "results": results }),
+    ))
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// btleplug only exposes the TX power level the device advertises; it has no
+// API for reading or changing the connection's transmit power.
+async fn tx_power_handler(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let p = connected_peripheral(&state).await?;
+    let props = p
+        .properties()
+        .await
+        .map_err(|e| ApiError::from_ble(&e.into()))?;
+    match props.and_then(|props| props.tx_power_level) {
+        Some(level) => Ok(Json(serde_json::json!({
+            "tx_power_level": level,
+            "source": "advertisement",
+        }))),
+        None => Err(ApiError::Unsupported(
+            "Device does not report its TX power level".to_string(),
+        )),
+    }
+}
+
+// Everything btleplug knows about the held device
+#[derive(Serialize, JsonSchema)]
+struct DeviceResponse {
+    address: String,
+    local_name: Option<String>,
+    rssi: Option<i16>,
+    /// Advertised TX power level, in dBm
+    tx_power_level: Option<i16>,
+    /// Hex payloads keyed by company identifier
+    manufacturer_data: BTreeMap<u16, String>,
+    /// Service UUIDs in the advertisement
+    advertised_services: Vec<Uuid>,
+    /// Services found by discovery after connecting
+    services: Vec<Uuid>,
+    characteristics: Vec<DeviceCharacteristic>,
+}
+
+#[derive(Serialize, JsonSchema)]
+struct DeviceCharacteristic {
+    uuid: Uuid,
+    service_uuid: Uuid,
+    /// e.g. WRITE, NOTIFY
+    properties: Vec<String>,
+}
+
+async fn device_handler(State(state): State<AppState>) -> Result<Json<DeviceResponse>, ApiError> {
+    let p = connected_peripheral(&state).await?;
+    let props = p
+        .properties()
+        .await
+        .map_err(|e| ApiError::from_ble(&e.into()))?
+        .unwrap_or_default();
+    Ok(Json(DeviceResponse {
+        address: p.address().to_string(),
+        local_name: props.local_name,
+        rssi: props.rssi,
+        tx_power_level: props.tx_power_level,
+        manufacturer_data: props
+            .manufacturer_data
+            .iter()
+            .map(|(&id, data)| (id, hex_string(data)))
+            .collect(),
+        advertised_services: props.services,
+        services: p.services().iter().map(|s| s.uuid).collect(),
+        characteristics: p
+            .characteristics()
+            .iter()
+            .map(|c| DeviceCharacteristic {
+                uuid: c.uuid,
+                service_uuid: c.service_uuid,
+                properties: c
+                    .properties
+                    .iter_names()
+                    .map(|(name, _)| name.to_string())
+                    .collect(),
+            })
+            .collect(),
+    }))
+}
+
+async fn set_tx_power_handler() -> ApiError {
+    ApiError::Unsupported("Setting TX power is not supported on this platform".to_string())
+}
+
+// Drop the BLE link on request. The monitor only reconnects a device it still
+// holds, so clearing the peripheral keeps it disconnected; user_disconnected
+// also stops a reconnect the monitor already has in progress.
+async fn disconnect_handler(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let _order = state.write_order.lock().await;
+    let p = state
+        .peripheral
+        .lock()
+        .await
+        .take()
+        .ok_or_else(|| ApiError::Conflict("No device connected".to_string()))?;
+    state.user_disconnected.store(true, Ordering::Relaxed);
+    link_dropped(&state);
+    state.events.publish(Event::Disconnected {
+        address: p.address().to_string(),
+    });
+
+    let _op = state.ble_op().await;
+    if let Some(ref message) = state.args.disconnect_message {
+        let write_type = state.args.write_type.into();
+        if let Err(e) = send_message(
+            &p,
+            state.display_target(p.address()),
+            message.as_bytes(),
+            state.write_options(write_type),
+        )
+        .await
+        {
+            warn!("Failed to send disconnect message: {}", e);
+        }
+    }
+    p.disconnect()
+        .await
+        .map_err(|e| ApiError::from_ble(&e.into()))?;
+    info!(event = "disconnect", address:% = p.address(); "Disconnected from {} on request", p.address());
+    Ok(Json(serde_json::json!({ "status": "disconnected" })))
+}
+
+// Drop the current device, if any, and have the monitor scan and connect
+// again (also after /disconnect). Answers once a device is connected (with
+// "ready": false if it lacks the display characteristic), once the
+// reconnect round fails, or with 504 after --reconnect-timeout-secs.
+async fn reconnect_handler(
+    State(state): State<AppState>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    if let Err(e) = state.adapter() {
+        return Err(ApiError::Unsupported(e.to_string()));
+    }
+    // Subscribe first so a quick reconnect isn't missed
+    let mut outcomes = state.round_outcomes.subscribe();
+    {
+        let _order = state.write_order.lock().await;
+        state.user_disconnected.store(false, Ordering::Relaxed);
+        let current = state.peripheral.lock().await.take();
+        if let Some(p) = current {
+            link_dropped(&state);
+            state.events.publish(Event::Disconnected {
+                address: p.address().to_string(),
+            });
+            let _op = state.ble_op().await;
+            if let Err(e) = p.disconnect().await {
+                warn!("Failed to disconnect {}: {}", p.address(), e);
+            }
+            info!("Disconnected from {} to reconnect", p.address());
+        }
+        state.reconnect_requested.notify_one();
+    }
+
+    let timeout = Duration::from_secs(state.args.reconnect_timeout_secs);
+    let outcome = time::timeout(timeout, async {
+        loop {
+            match outcomes.recv().await {
+                // Whichever round connects, even one already under way
+                Ok(outcome @ RoundOutcome::Connected { .. }) => return Some(outcome),
+                // Only a round started for this request counts as its failure
+                Ok(
+                    outcome @ RoundOutcome::Failed {
+                        reason: ConnectReason::Manual,
+                        ..
+                    },
+                ) => return Some(outcome),
+                Err(broadcast::error::RecvError::Closed) => return None,
+                _ => {}
+            }
+        }
+    })
+    .await;
+    match outcome {
+        Ok(Some(RoundOutcome::Connected { address, ready })) => {
+            let name = state
+                .last_device
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|d| d.name.clone());
+            Ok(Json(serde_json::json!({
+                "status": "connected",
+                "address": address.to_string(),
+                "name": name,
+                "ready": ready,
+            })))
+        }
+        Ok(Some(RoundOutcome::Failed { error, .. })) => {
+            Err(ApiError::Ble(format!("Reconnect failed: {}", error)))
+        }
+        _ => Err(ApiError::Timeout(format!(
+            "No device reconnected within {}s",
+            timeout.as_secs()
+        ))),
+    }
+}
+
+// A running scan. Only one scan runs at a time across main and the monitor
+// (platforms reject concurrent scans on one adapter), so starting waits for
+// any other scan to be stopped.
+struct Scan {
+    _lock: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl Scan {
+    async fn start(
+        state: &AppState,
+        adapter: &Adapter,
+        filter: ScanFilter,
+    ) -> anyhow::Result<Scan> {
+        let lock = state.scan_lock.clone().lock_owned().await;
+        adapter.start_scan(filter).await?;
+        Ok(Scan { _lock: lock })
+    }
+
+    // Some platforms fail a stop when no scan is active; that shouldn't
+    // end the retry loop, so the error is only logged
+    async fn stop(self, adapter: &Adapter) {
+        if let Err(e) = adapter.stop_scan().await {
+            warn!("Failed to stop scan: {}", e);
+        }
+    }
+}
+
+// Sleep for `duration`, returning true early if shutdown was requested
+async fn sleep_or_stop(duration: Duration, stop: &mut tokio::sync::watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = time::sleep(duration) => false,
+        _ = stop.wait_for(|stopped| *stopped) => true,
+    }
+}
+
+// With --trace-ble, log the adapter's events in the background; without it
+// they aren't even subscribed to
+async fn start_ble_trace(args: &Args, adapter: &Adapter) -> anyhow::Result<()> {
+    if args.trace_ble {
+        let events = adapter.events().await?;
+        tokio::spawn(trace_ble_events(events));
+    }
+    Ok(())
+}
+
+// Log every adapter event for --trace-ble: discovery, advertisement updates,
+// connects and disconnects, as the platform reports them
+async fn trace_ble_events(
+    mut events: std::pin::Pin<Box<dyn futures::Stream<Item = CentralEvent> + Send>>,
+) {
+    while let Some(event) = events.next().await {
+        trace!(event = "ble_trace"; "BLE event: {:?}", event);
+    }
+    debug!("BLE event stream ended");
+}
+
+// Wait for the next tick of an optional interval; never ready without one
+async fn next_tick(interval: &mut Option<time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// One-line heartbeat for --health-log-secs. Counts are since the previous
+// summary, which `baseline` tracks.
+async fn log_health(state: &AppState, baseline: &mut StatsSnapshot) {
+    let (address, connected) = match state.peripheral.lock().await.as_ref() {
+        Some(p) => (
+            p.address().to_string(),
+            p.is_connected().await.unwrap_or(false),
+        ),
+        None => ("none".to_string(), false),
+    };
+    let rssi = state
+        .rssi_history
+        .samples()
+        .last()
+        .map_or("n/a".to_string(), |s| format!("{} dBm", s.rssi));
+    let now = state.stats.snapshot();
+    let (sent, errors) = health_counts(&now, baseline);
+    info!(
+        "Health: connected={} address={} rssi={} sent={} errors={}",
+        connected, address, rssi, sent, errors
+    );
+    *baseline = now;
+}
+
+// Writes sent and failed (including expired and rejected) since `baseline`
+fn health_counts(now: &StatsSnapshot, baseline: &StatsSnapshot) -> (u64, u64) {
+    let errors = |s: &StatsSnapshot| s.writes_failed + s.expired + s.rejected;
+    (
+        now.writes_ok - baseline.writes_ok,
+        errors(now) - errors(baseline),
+    )
+}
+
+// BLE monitor task: make the first connection, then watch for disconnect and
+// reconnect until `stop` is set
+async fn ble_monitor_task(
+    state: AppState,
+    mut stop: tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(2));
+    let retry_interval = Duration::from_secs(state.args.reconnect_interval_secs);
+    let max_backoff = Duration::from_secs(state.args.max_reconnect_secs).max(retry_interval);
+    let mut health = state.args.health_log_secs.map(|secs| {
+        let period = Duration::from_secs(secs);
+        time::interval_at(time::Instant::now() + period, period)
+    });
+    let mut health_baseline = state.stats.snapshot();
+
+    let Some(target) = connect_at_startup(&state, stop.clone()).await else {
+        info!("Stopped scanning for shutdown");
+        return Ok(());
+    };
+    if hold_peripheral(&state, target).await {
+        info!(event = "connected"; "Device ready");
+    }
+
+    loop {
+        let wake = tokio::select! {
+            _ = interval.tick() => Wake::Tick,
+            _ = state.link_check.notified() => Wake::Tick,
+            _ = next_tick(&mut health) => Wake::Health,
+            _ = state.reconnect_requested.notified() => Wake::Reconnect,
+            _ = stop.wait_for(|stopped| *stopped) => return Ok(()),
+        };
+        let reason = match wake {
+            Wake::Health => {
+                log_health(&state, &mut health_baseline).await;
+                continue;
+            }
+            // POST /reconnect has already dropped the device; nothing to do
+            // if a reconnect beat it to the punch
+            Wake::Reconnect if state.peripheral.lock().await.is_none() => ConnectReason::Manual,
+            Wake::Reconnect => continue,
+            Wake::Tick if check_link(&state).await => continue,
+            Wake::Tick => ConnectReason::Reconnect,
+        };
+        let mut reason = reason;
+        let previous = state.last_device.lock().unwrap().clone();
+
+        // Back off while the device stays away; restarts at
+        // --reconnect-interval-secs after each disconnect
+        let mut backoff = retry_interval;
+        loop {
+            if state.user_disconnected.load(Ordering::Relaxed) {
+                info!("Disconnected on request, not reconnecting");
+                break;
+            }
+            info!(event = "scan_start"; "Scanning for devices...");
+            *state.phase.lock().unwrap() = LinkPhase::Connecting;
+            let failed = |error: String| {
+                state
+                    .round_outcomes
+                    .send(RoundOutcome::Failed { reason, error })
+                    .ok();
+            };
+            match scan_and_connect(&state, reason, previous.as_ref(), &mut stop).await {
+                // Retried like a failed connect rather than ending the monitor
+                Err(e) => {
+                    warn!("Scan failed: {:#}", e);
+                    failed(format!("{:#}", e));
+                }
+                Ok(Attempt::Stopped) => {
+                    info!("Stopped scanning for shutdown");
+                    return Ok(());
+                }
+                // /disconnect arrived while we were connecting
+                Ok(Attempt::Connected(target))
+                    if state.user_disconnected.load(Ordering::Relaxed) =>
+                {
+                    target.disconnect().await.ok();
+                    info!("Disconnected on request, not reconnecting");
+                    break;
+                }
+                Ok(Attempt::Connected(target)) => {
+                    if hold_peripheral(&state, target).await {
+                        info!(event = "reconnected"; "Reconnected successfully");
+                    }
+                    break;
+                }
+                Ok(Attempt::Failed(_, e)) => {
+                    warn!("Reconnect failed: {}", e);
+                    failed(format!("{:#}", e));
+                }
+                Ok(Attempt::NotFound) => failed("Target device not found".to_string()),
+            }
+            info!(event = "reconnect", delay_secs = backoff.as_secs(); "Retrying reconnect in {}s", backoff.as_secs());
+            tokio::select! {
+                _ = time::sleep(backoff) => backoff = next_backoff(backoff, max_backoff),
+                // POST /reconnect: try again now, and back off from the start
+                _ = state.reconnect_requested.notified() => {
+                    reason = ConnectReason::Manual;
+                    backoff = retry_interval;
+                }
+                _ = stop.wait_for(|stopped| *stopped) => return Ok(()),
+            }
+        }
+    }
+}
+
+// Double the reconnect wait, up to --max-reconnect-secs
+fn next_backoff(backoff: Duration, max: Duration) -> Duration {
+    (backoff * 2).min(max)
+}
+
+// What woke the monitor loop
+enum Wake {
+    Tick,
+    Health,
+    Reconnect,
+}
+
+// Check the held device and sample its RSSI. Returns false once the link is
+// found dead, after dropping the device.
+async fn check_link(state: &AppState) -> bool {
+    let mut peripheral = state.peripheral.lock().await;
+    let Some(ref p) = *peripheral else {
+        return true;
+    };
+    let supervision_expired = state.args.supervision_timeout_ms.is_some_and(|ms| {
+        state
+            .supervision
+            .expired(Duration::from_millis(ms), std::time::Instant::now())
+    });
+    if supervision_expired {
+        warn!("Device stopped answering, treating the link as dead");
+        p.disconnect().await.ok();
+    }
+    // A write already found the device gone
+    let reported_lost = *state.phase.lock().unwrap() == LinkPhase::Disconnected;
+    if reported_lost {
+        p.disconnect().await.ok();
+    }
+    match p.is_connected().await {
+        Ok(true) if !supervision_expired && !reported_lost => {
+            debug!("Device connected");
+            if let Ok(Some(rssi)) = p.properties().await.map(|props| props?.rssi) {
+                state.rssi_history.push(rssi);
+            }
+            true
+        }
+        _ => {
+            warn!(event = "disconnect", address:% = p.address(); "Device disconnected!");
+            state.events.publish(Event::Disconnected {
+                address: p.address().to_string(),
+            });
+            *peripheral = None;
+            drop(peripheral);
+            link_dropped(state);
+            false
+        }
+    }
+}
+
+// Reset what doesn't survive the link: subscriptions, pending supervision,
+// and the last message and status (the display needs redrawing after a
+// reconnect)
+fn link_dropped(state: &AppState) {
+    *state.phase.lock().unwrap() = LinkPhase::Disconnected;
+    state.subscriptions.lock().unwrap().clear();
+    state.supervision.success();
+    forget_display(state);
+}
+
+// A device to look for first when reconnecting (see find_previous_device)
+#[derive(Clone)]
+struct PreviousDevice {
+    address: BDAddr,
+    name: Option<String>,
+}
+
+// Outcome of one scan_and_connect round
+enum Attempt {
+    Connected(PlatformPeripheral),
+    NotFound,
+    Failed(BDAddr, anyhow::Error),
+    // Shutdown was requested during the scan
+    Stopped,
+}
+
+// One scan-and-connect round, shared by startup and the monitor (including
+// reconnects asked for by POST /reconnect): scan for --scan-secs, pick
+// `previous` if it is seen or else the best match, and connect to it.
+async fn scan_and_connect(
+    state: &AppState,
+    reason: ConnectReason,
+    previous: Option<&PreviousDevice>,
+    stop: &mut tokio::sync::watch::Receiver<bool>,
+) -> anyhow::Result<Attempt> {
+    let adapter = state.adapter()?;
+    // It may have been turned off since startup
+    ensure_powered_on(adapter).await?;
+    let scan = Scan::start(state, adapter, scan_filter(&state.args)).await?;
+    if sleep_or_stop(Duration::from_secs(state.args.scan_secs), stop).await {
+        scan.stop(adapter).await;
+        return Ok(Attempt::Stopped);
+    }
+
+    // Stop the scan even if listing the devices fails
+    let target = async {
+        let peripherals = list_peripherals(state).await?;
+        info!("Found {} devices", peripherals.len());
+        let target = match previous {
+            Some(previous) => {
+                find_previous_device(
+                    &peripherals,
+                    &state.args,
+                    previous.address,
+                    previous.name.as_deref(),
+                )
+                .await?
+            }
+            None => None,
+        };
+        match target {
+            Some(p) => Ok(Some(p)),
+            None => select_target(&peripherals, state.args.service_uuid, state).await,
+        }
+    }
+    .await;
+    scan.stop(adapter).await;
+
+    let Some(target) = target? else {
+        return Ok(Attempt::NotFound);
+    };
+    match connect_and_discover(&target, state, reason).await {
+        Ok(()) => Ok(Attempt::Connected(target)),
+        Err(e) => Ok(Attempt::Failed(target.address(), e)),
+    }
+}
+
+// The adapter's device list, asked again a few times (see
+// --peripherals-retries) if it comes back empty or the query fails: some
+// platforms are slow to publish what the scan saw. The last answer stands.
+async fn list_peripherals(state: &AppState) -> anyhow::Result<Vec<PlatformPeripheral>> {
+    let adapter = state.adapter()?;
+    let retries = state.args.peripherals_retries;
+    let delay = Duration::from_millis(state.args.peripherals_retry_ms);
+    let mut attempt = 0;
+    loop {
+        let listed = adapter.peripherals().await;
+        match &listed {
+            Ok(peripherals) if !peripherals.is_empty() => return Ok(listed?),
+            _ if attempt == retries => return Ok(listed?),
+            Ok(_) => debug!(
+                "No devices listed yet, asking again ({}/{})",
+                attempt + 1,
+                retries
+            ),
+            Err(e) => debug!(
+                "Listing devices failed, asking again ({}/{}): {}",
+                attempt + 1,
+                retries,
+                e
+            ),
+        }
+        attempt += 1;
+        time::sleep(delay).await;
+    }
+}
+
+// Make a freshly connected device the current one and announce it if it has
+// the display characteristic; returns whether it does
+async fn hold_peripheral(state: &AppState, p: PlatformPeripheral) -> bool {
+    let address = p.address();
+    // Without --require-char a device lacking the display characteristic is
+    // kept, but no write to it can succeed
+    let ready = resolve_characteristic(&p.services(), state.display_target(address)).is_some();
+    let name = p
+        .properties()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|props| props.local_name);
+    if let Some(path) = &state.args.state_file {
+        let saved = SavedState {
+            last_address: Some(address.to_string()),
+            last_name: name.clone(),
+        };
+        if let Err(e) = saved.save(path) {
+            warn!("Failed to save last device: {:#}", e);
+        }
+    }
+    *state.last_device.lock().unwrap() = Some(PreviousDevice { address, name });
+    {
+        // With --buffer-while-disconnected, a /send either saw the link down
+        // and was buffered, or sees it up and queues after the backlog
+        let _order = match &state.offline {
+            Some(_) => Some(state.write_order.lock().await),
+            None => None,
+        };
+        *state.peripheral.lock().await = Some(p);
+        *state.phase.lock().unwrap() = LinkPhase::Connected;
+        if ready {
+            flush_offline(state);
+        }
+    }
+    if ready {
+        state.events.publish(Event::Connected {
+            address: address.to_string(),
+        });
+    }
+    state
+        .round_outcomes
+        .send(RoundOutcome::Connected { address, ready })
+        .ok();
+    ready
+}
+
+// Queue what was buffered while disconnected, oldest first. All at normal
+// priority, whatever X-Priority each came with, so the order is kept.
+fn flush_offline(state: &AppState) {
+    let Some(offline) = &state.offline else {
+        return;
+    };
+    let buffered = offline.drain();
+    if buffered.is_empty() {
+        return;
+    }
+    info!(
+        "Writing {} messages buffered while disconnected",
+        buffered.len()
+    );
+    for write in buffered {
+        let group = state.write_queue.next_group();
+        for part in &write.parts {
+            if let Err(e) = enqueue_write(state, part, write.write_type, Priority::Normal, group) {
+                warn!("Dropped a buffered message: {}", e);
+            }
+        }
+    }
+}
+
+// The device --state-file remembers, unless --device-address or
+// --device-name now ask for another
+fn saved_device(args: &Args) -> Option<PreviousDevice> {
+    let saved = SavedState::load(args.state_file.as_deref()?);
+    let address = BDAddr::from_str_delim(saved.last_address.as_deref()?).ok()?;
+    if !device_filter_matches(args, address, saved.last_name.as_deref()) {
+        return None;
+    }
+    Some(PreviousDevice {
+        address,
+        name: saved.last_name,
+    })
+}
+
+// Connect to the saved device without scanning, if the adapter still lists
+// it (BlueZ keeps paired and recently seen devices). Anything else falls back
+// to a scan, which also looks for the saved device first.
+async fn connect_saved_device(
+    state: &AppState,
+    saved: &PreviousDevice,
+) -> Option<PlatformPeripheral> {
+    let peripherals = state.adapter().ok()?.peripherals().await.ok()?;
+    let Some(p) = peripherals
+        .into_iter()
+        .find(|p| p.address() == saved.address)
+    else {
+        debug!("Last device {} not known to the adapter", saved.address);
+        return None;
+    };
+    // It may have been renamed since it was saved
+    let name = p
+        .properties()
+        .await
+        .ok()
+        .flatten()
+        .and_then(|props| props.local_name);
+    if !device_filter_matches(&state.args, saved.address, name.as_deref()) {
+        debug!(
+            "Last device {} no longer matches the device filters",
+            saved.address
+        );
+        return None;
+    }
+    info!(
+        "Connecting to last device {} without scanning...",
+        saved.address
+    );
+    match connect_and_discover(&p, state, ConnectReason::Initial).await {
+        Ok(()) => Some(p),
+        Err(e) => {
+            warn!(
+                "Last device {} unavailable ({}), scanning",
+                saved.address, e
+            );
+            None
+        }
+    }
+}
+
+// Remove the persisted state file; a missing file is already a clean slate
+fn reset_state(path: &std::path::Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Ok(()) => info!("Cleared saved state {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("No saved state at {}, nothing to clear", path.display())
+        }
+        Err(e) => anyhow::bail!("Failed to clear saved state {}: {}", path.display(), e),
+    }
+    Ok(())
+}
+
+// Scans fail on a powered-off adapter. btleplug can't change the power state,
+// so on Linux ask BlueZ through bluetoothctl; elsewhere report it clearly.
+async fn ensure_powered_on(adapter: &Adapter) -> anyhow::Result<()> {
+    let mut power_on = tokio::process::Command::new("bluetoothctl");
+    power_on.args(["power", "on"]);
+    ensure_powered_on_with(adapter, power_on).await
+}
+
+// ensure_powered_on, running `power_on` to turn the adapter on
+async fn ensure_powered_on_with(
+    adapter: &Adapter,
+    mut power_on: tokio::process::Command,
+) -> anyhow::Result<()> {
+    match adapter.adapter_state().await? {
+        CentralState::PoweredOff => {}
+        state => {
+            debug!("Adapter state: {:?}", state);
+            return Ok(());
+        }
+    }
+
+    if !cfg!(target_os = "linux") {
+        anyhow::bail!("Bluetooth adapter is powered off; turn Bluetooth on and try again");
+    }
+
+    warn!("Bluetooth adapter is powered off, trying to power it on...");
+    let output = power_on.output().await;
+    match output {
+        Ok(out) if out.status.success() => {}
+        Ok(out) => anyhow::bail!(
+            "Bluetooth adapter is powered off and could not be powered on: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        ),
+        Err(e) => anyhow::bail!(
+            "Bluetooth adapter is powered off and bluetoothctl is unavailable: {}",
+            e
+        ),
+    }
+
+    time::sleep(Duration::from_secs(1)).await;
+    if let CentralState::PoweredOff = adapter.adapter_state().await? {
+        anyhow::bail!("Bluetooth adapter is still powered off; power-on may not be permitted");
+    }
+    info!("Bluetooth adapter powered on");
+    Ok(())
+}
+
+// Scan filter shared by the startup scan and reconnects. Post-scan filtering
+// in find_and_print_peripherals still checks the service either way.
+fn scan_filter(args: &Args) -> ScanFilter {
+    let mut filter = ScanFilter::default();
+    // Probing needs to see devices that don't advertise the service
+    if !args.probe {
+        filter.services.push(args.service_uuid);
+    }
+    filter
+}
+
+// Pick the device to connect to, falling back to probing (see --probe)
+async fn select_target(
+    peripherals: &[PlatformPeripheral],
+    target_service: Uuid,
+    state: &AppState,
+) -> anyhow::Result<Option<PlatformPeripheral>> {
+    let ranked = find_and_print_peripherals(peripherals, target_service, state).await?;
+    let concurrency = usize::from(state.args.connect_concurrency);
+    if ranked.len() > 1 && concurrency > 1 {
+        let top = &ranked[..concurrency.min(ranked.len())];
+        return Ok(race_connect(top).await);
+    }
+    if let Some(p) = ranked.into_iter().next() {
+        return Ok(Some(p));
+    }
+    if !state.args.probe {
+        return Ok(None);
+    }
+    let mut wanted = Vec::new();
+    for p in peripherals {
+        let name = p.properties().await?.and_then(|props| props.local_name);
+        if device_filter_matches(&state.args, p.address(), name.as_deref()) {
+            wanted.push(p.clone());
+        }
+    }
+    let _op = state.ble_op().await;
+    Ok(probe_peripherals(&wanted, target_service, state.args.max_probes).await)
+}
+
+// The device we just lost, by address or, failing that, by name. Devices
+// using resolvable private addresses advertise a new address from time to
+// time, so a device with the target service and the same local name is
+// taken to be the same one. btleplug doesn't expose IRKs, so the address
+// can't be resolved directly.
+async fn find_previous_device(
+    peripherals: &[PlatformPeripheral],
+    args: &Args,
+    last_addr: BDAddr,
+    last_name: Option<&str>,
+) -> anyhow::Result<Option<PlatformPeripheral>> {
+    // Only devices the filters allow, so a name match can't override a
+    // pinned --device-address
+    let mut seen = Vec::new();
+    for p in peripherals {
+        if let Some(props) = p.properties().await? {
+            if props.services.contains(&args.service_uuid)
+                && device_filter_matches(args, p.address(), props.local_name.as_deref())
+            {
+                seen.push((p, p.address(), props.local_name));
+            }
+        }
+    }
+    let found = pick_previous(&seen, last_addr, last_name);
+    if let Some(&(p, addr, _)) = found {
+        if addr != last_addr {
+            info!(
+                "{} now advertises as {}, reconnecting by name",
+                last_addr, addr
+            );
+        }
+        return Ok(Some(p.clone()));
+    }
+    Ok(None)
+}
+
+fn pick_previous<'a, T>(
+    seen: &'a [(T, BDAddr, Option<String>)],
+    last_addr: BDAddr,
+    last_name: Option<&str>,
+) -> Option<&'a (T, BDAddr, Option<String>)> {
+    seen.iter()
+        .find(|(_, addr, _)| *addr == last_addr)
+        .or_else(|| {
+            let last_name = last_name?;
+            seen.iter()
+                .find(|(_, _, name)| name.as_deref() == Some(last_name))
+        })
+}
+
+// --device-name (case-insensitive) and --device-address; with both set a
+// device must match both. Unset filters match everything.
+fn device_filter_matches(args: &Args, addr: BDAddr, name: Option<&str>) -> bool {
+    let name_ok = args
+        .device_name
+        .as_ref()
+        .is_none_or(|wanted| name.is_some_and(|name| name.to_lowercase() == wanted.to_lowercase()));
+    let addr_ok = args.device_address.is_none_or(|wanted| wanted == addr);
+    name_ok && addr_ok
+}
+
+// Connect to each candidate in turn and keep the first that exposes the
+// target service after discovery; the others are disconnected again
+async fn probe_peripherals(
+    peripherals: &[PlatformPeripheral],
+    target_service: Uuid,
+    max_probes: usize,
+) -> Option<PlatformPeripheral> {
+    for peripheral in peripherals.iter().take(max_probes) {
+        let addr = peripheral.address();
+        info!("Probing {} for target service...", addr);
+        let probe = async {
+            peripheral.connect().await?;
+            peripheral.discover_services().await?;
+            anyhow::Ok(
+                peripheral
+                    .services()
+                    .iter()
+                    .any(|s| s.uuid == target_service),
+            )
+        };
+        match probe.await {
+            Ok(true) => {
+                info!("    >>> Found target service on {}", addr);
+                return Some(peripheral.clone());
+            }
+            Ok(false) => debug!("    {} does not expose target service", addr),
+            Err(e) => warn!("    Probe of {} failed: {}", addr, e),
+        }
+        peripheral.disconnect().await.ok();
+    }
+    None
+}
+
+// Find and list peripherals with target service
+async fn find_and_print_peripherals(
+    peripherals: &[PlatformPeripheral],
+    target_service: Uuid,
+    state: &AppState,
+) -> anyhow::Result<Vec<PlatformPeripheral>> {
+    let mut candidates = Vec::new();
+
+    for peripheral in peripherals {
+        let addr = peripheral.address();
+        if let Some(props) = peripheral.properties().await? {
+            let name = props.local_name.clone().unwrap_or("(unknown)".to_string());
+            let rssi = props.rssi.unwrap_or(0);
+            let log_now = state.scan_log.lock().unwrap().should_log(
+                addr,
+                &name,
+                rssi,
+                std::time::Instant::now(),
+            );
+            if log_now {
+                info!(event = "found", address:% = addr, rssi; "  {} - {} (RSSI: {})", addr, name, rssi);
+            } else {
+                debug!("  {} - {} (RSSI: {})", addr, name, rssi);
+            }
+
+            for service in &props.services {
+                debug!("    Service UUID: {}", service);
+            }
+
+            let has_target_service = props.services.contains(&target_service);
+            let wanted = device_filter_matches(&state.args, addr, props.local_name.as_deref());
+
+            if has_target_service && !wanted {
+                debug!("    Skipped by --device-name/--device-address");
+            } else if has_target_service {
+                info!("    >>> Found target service!");
+                let priority = state
+                    .args
+                    .device_priority
+                    .iter()
+                    .find(|(a, _)| *a == addr)
+                    .map(|(_, p)| *p);
+                let last_write = state.last_write_ok.lock().unwrap().get(&addr).copied();
+                candidates.push((peripheral.clone(), (priority, last_write, props.rssi)));
+            }
+
+            debug!("----------------------------");
+        }
+    }
+
+    Ok(rank_candidates(candidates))
+}
+
+// (priority from --device-priority, last successful write, RSSI). `None`
+// ranks below any value, so unlisted devices, devices never written to and
+// missing RSSI come last.
+type SelectionRank = (Option<i32>, Option<std::time::Instant>, Option<i16>);
+
+// Best first; among equal ranks the first seen comes first
+fn rank_candidates<T>(mut candidates: Vec<(T, SelectionRank)>) -> Vec<T> {
+    candidates.sort_by_key(|(_, rank)| std::cmp::Reverse(*rank));
+    candidates
+        .into_iter()
+        .map(|(candidate, _)| candidate)
+        .collect()
+}
+
+// Connect to every candidate at once and keep the first to succeed; the
+// rest are disconnected, whether or not they have connected yet, since a
+// dropped connect may still complete at the platform level. These connects
+// bypass --max-ble-ops, since racing them is the point.
+async fn race_connect(candidates: &[PlatformPeripheral]) -> Option<PlatformPeripheral> {
+    let attempts = candidates.iter().map(|p| async move {
+        match p.connect().await {
+            Ok(()) => Some(p),
+            Err(e) => {
+                warn!("Connect to {} failed: {}", p.address(), e);
+                None
+            }
+        }
+    });
+    let winner = first_success(attempts).await.cloned();
+    for p in candidates {
+        let won = winner.as_ref().is_some_and(|w| w.address() == p.address());
+        if !won {
+            debug!("Dropping slower candidate {}", p.address());
+            p.disconnect().await.ok();
+        }
+    }
+    if let Some(ref p) = winner {
+        info!("{} connected first", p.address());
+    }
+    winner
+}
+
+// Output of the first attempt to produce Some; the others are dropped
+async fn first_success<T>(
+    attempts: impl IntoIterator<Item = impl Future<Output = Option<T>>>,
+) -> Option<T> {
+    let mut pending: futures::stream::FuturesUnordered<_> = attempts.into_iter().collect();
+    while let Some(result) = pending.next().await {
+        if result.is_some() {
+            return result;
+        }
+    }
+    None
+}
+
+// Pause between --connect-retries attempts
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Pause before each --discover-retries pass
+const DISCOVER_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// peripheral.connect() with a --connect-timeout-secs limit on each attempt,
+// tried again up to --connect-retries times
+async fn connect_with_retries(
+    peripheral: &PlatformPeripheral,
+    state: &AppState,
+) -> anyhow::Result<()> {
+    let addr = peripheral.address();
+    let timeout = Duration::from_secs(state.args.connect_timeout_secs);
+    let attempts = state.args.connect_retries + 1;
+    let mut attempt = 1;
+    loop {
+        let result = {
+            let _op = state.ble_op().await;
+            time::timeout(timeout, peripheral.connect()).await
+        };
+        let err = match result {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e,
+            Err(_) => {
+                // Cancel the pending connection so the next attempt starts fresh
+                peripheral.disconnect().await.ok();
+                btleplug::Error::TimedOut(timeout)
+            }
+        };
+        warn!(
+            "Connect attempt {}/{} to {} failed: {}",
+            attempt, attempts, addr, err
+        );
+        if attempt == attempts {
+            return Err(err.into());
+        }
+        attempt += 1;
+        time::sleep(CONNECT_RETRY_DELAY).await;
+    }
+}
+
+// Connect to device and discover services
+async fn connect_and_discover(
+    peripheral: &PlatformPeripheral,
+    state: &AppState,
+    reason: ConnectReason,
+) -> anyhow::Result<()> {
+    let addr = peripheral.address();
+    info!(event = "connect", address:% = addr; "Connecting to {} ({:?})...", addr, reason);
+
+    // Already connected if it won a race_connect
+    if !peripheral.is_connected().await.unwrap_or(false) {
+        connect_with_retries(peripheral, state).await?;
+    }
+    let settle = Duration::from_secs(state.args.connect_settle_secs);
+    time::sleep(settle).await;
+
+    info!("Discovering services...");
+    {
+        let _op = state.ble_op().await;
+        peripheral.discover_services().await?;
+    }
+    time::sleep(settle).await;
+
+    // The same lookup writes use, so --service-instance counts here too
+    let display_target = state.display_target(addr);
+    let mut display = resolve_characteristic(&peripheral.services(), display_target);
+    let mut characteristics = peripheral.characteristics();
+    for attempt in 1..=state.args.discover_retries {
+        if display.is_some() {
+            break;
+        }
+        info!(
+            "{} not discovered yet, discovering again ({}/{})",
+            display_target.characteristic, attempt, state.args.discover_retries
+        );
+        time::sleep(DISCOVER_RETRY_DELAY).await;
+        {
+            let _op = state.ble_op().await;
+            peripheral.discover_services().await?;
+        }
+        display = resolve_characteristic(&peripheral.services(), display_target);
+        characteristics = peripheral.characteristics();
+    }
+    info!("Found {} characteristics", characteristics.len());
+
+    let Some(display) = display else {
+        let problem = missing_char_message(
+            &peripheral.services(),
+            state.args.service_uuid,
+            display_target.characteristic,
+        );
+        if state.args.require_char {
+            peripheral.disconnect().await.ok();
+            anyhow::bail!("{}", problem);
+        }
+        warn!("{} on {}, writes will fail", problem, addr);
+        return Ok(());
+    };
+
+    // Subscribed again on every connect, so this also resubscribes after a
+    // reconnect
+    if let Some(uuid) = state.args.notify_uuid {
+        match characteristics.iter().find(|c| c.uuid == uuid) {
+            Some(c)
+                if c.properties
+                    .intersects(CharPropFlags::NOTIFY | CharPropFlags::INDICATE) =>
+            {
+                let _op = state.ble_op().await;
+                peripheral.subscribe(c).await?;
+                start_notify_consumer(state, peripheral).await?;
+                info!("Subscribed to {} for notifications", uuid);
+            }
+            Some(_) => warn!("Characteristic {} does not support notifications", uuid),
+            None => warn!("Notify characteristic {} not found", uuid),
+        }
+    }
+
+    if let Some(uuid) = state.args.verify_notify_uuid {
+        match characteristics.iter().find(|c| c.uuid == uuid) {
+            Some(c)
+                if c.properties
+                    .intersects(CharPropFlags::NOTIFY | CharPropFlags::INDICATE) =>
+            {
+                let _op = state.ble_op().await;
+                peripheral.subscribe(c).await?;
+                info!("Subscribed to {} for write verification", uuid);
+            }
+            Some(_) => warn!("Characteristic {} does not support notifications", uuid),
+            None => warn!("Verification characteristic {} not found", uuid),
+        }
+    }
+
+    if state.args.auto_mtu {
+        let probed = {
+            let _op = state.ble_op().await;
+            probe_mtu(peripheral, &display).await
+        };
+        let size = probed.or(Some(state.args.chunk_size.into()).filter(|&size| size > 0));
+        info!("Using write chunk size: {:?}", size);
+        state.chunk_size.store(size.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    // Greet after a successful connection
+    let greeting = state.connect_message(reason);
+    let _op = state.ble_op().await;
+    send_message(
+        peripheral,
+        state.display_target(addr),
+        greeting.as_bytes(),
+        state.write_options(WriteType::WithResponse),
+    )
+    .await?;
+
+    Ok(())
+}
+
+// Log notifications from --notify-uuid and any characteristic subscribed to
+// with POST /subscribe, and buffer them for GET /events, replacing the
+// consumer left over from a previous connection or subscription
+async fn start_notify_consumer(
+    state: &AppState,
+    peripheral: &PlatformPeripheral,
+) -> anyhow::Result<()> {
+    let mut stream = peripheral.notifications().await?;
+    let task_state = state.clone();
+    let task = tokio::spawn(async move {
+        while let Some(n) = stream.next().await {
+            let uuid = n.uuid;
+            let wanted = task_state.args.notify_uuid == Some(uuid)
+                || task_state.subscriptions.lock().unwrap().contains(&uuid);
+            if !wanted {
+                continue;
+            }
+            info!("Notification from {}: {}", uuid, hex_string(&n.value));
+            let notification = Notification::new(uuid, &n.value);
+            task_state.events.publish(Event::Notification {
+                uuid,
+                value: notification.value.clone(),
+                hex: notification.hex.clone(),
+            });
+            task_state.notifications.push(notification);
+        }
+    });
+    if let Some(previous) = state.notify_task.lock().unwrap().replace(task) {
+        previous.abort();
+    }
+    Ok(())
+}
+
+// Explain a missing write characteristic. A missing service usually means
+// the wrong device; a service without the characteristic usually means a
+// mistyped characteristic UUID.
+fn missing_char_message(
+    services: &BTreeSet<Service>,
+    service: Uuid,
+    characteristic: Uuid,
+) -> String {
+    if services.iter().any(|s| s.uuid == service) {
+        format!(
+            "Service {} found but characteristic {} is missing; check the characteristic UUID",
+            service, characteristic
+        )
+    } else {
+        format!(
+            "Service {} not found; this is probably not the controller device",
+            service
+        )
+    }
+}
+
+// Write sizes tried by --auto-mtu, smallest first; 512 is the ATT maximum
+const MTU_PROBE_SIZES: &[usize] = &[20, 64, 128, 182, 244, 512];
+
+// Find the largest probe write the device acknowledges. Probing stops at the
+// first failure since a larger size won't succeed either.
+async fn probe_mtu(peripheral: &PlatformPeripheral, display: &Characteristic) -> Option<usize> {
+    let mut results = Vec::new();
+    for &size in MTU_PROBE_SIZES {
+        let ok = peripheral
+            .write(display, &vec![b' '; size], WriteType::WithResponse)
+            .await
+            .is_ok();
+        debug!(
+            "MTU probe of {} bytes: {}",
+            size,
+            if ok { "ok" } else { "failed" }
+        );
+        results.push((size, ok));
+        if !ok {
+            break;
+        }
+    }
+    largest_success(&results)
+}
+
+fn largest_success(results: &[(usize, bool)]) -> Option<usize> {
+    results
+        .iter()
+        .take_while(|(_, ok)| *ok)
+        .map(|(size, _)| *size)
+        .last()
+}
+
+// Send message to characteristic
+async fn send_message(
+    peripheral: &PlatformPeripheral,
+    target: CharTarget,
+    data: &[u8],
+    options: WriteOptions,
+) -> anyhow::Result<()> {
+    let Some(char) = resolve_characteristic(&peripheral.services(), target) else {
+        return Err(MissingCharacteristic(target.characteristic).into());
+    };
+    info!("Found target characteristic: {}", char.uuid);
+    info!("Sending data: {}", String::from_utf8_lossy(data));
+
+    match options.chunk_size {
+        Some(size) if data.len() > size => {
+            for (i, chunk) in split_chunks(data, size).into_iter().enumerate() {
+                if i > 0 && !options.chunk_delay.is_zero() {
+                    time::sleep(options.chunk_delay).await;
+                }
+                write_chunk(peripheral, &char, chunk, options).await?;
+            }
+        }
+        _ => write_chunk(peripheral, &char, data, options).await?,
+    }
+
+    info!("Data sent successfully");
+    Ok(())
+}
+
+// Split `data` into chunks of at most `size` bytes. UTF-8 text is only split
+// between characters, so a multibyte character never straddles two writes
+// (unless `size` is smaller than the character itself).
+fn split_chunks(data: &[u8], size: usize) -> Vec<&[u8]> {
+    if std::str::from_utf8(data).is_err() {
+        return data.chunks(size).collect();
+    }
+    let is_continuation = |b: u8| b & 0xc0 == 0x80;
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let limit = (start + size).min(data.len());
+        let mut end = limit;
+        while end > start && end < data.len() && is_continuation(data[end]) {
+            end -= 1;
+        }
+        if end == start {
+            end = limit;
+        }
+        chunks.push(&data[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+#[derive(Clone, Copy)]
+struct WriteOptions {
+    write_type: WriteType,
+    chunk_size: Option<usize>,
+    chunk_delay: Duration,
+    // How long to wait for an acknowledgement before retrying without one
+    // (see --fallback-write-type)
+    fallback_after: Option<Duration>,
+}
+
+async fn write_chunk(
+    peripheral: &PlatformPeripheral,
+    char: &Characteristic,
+    chunk: &[u8],
+    options: WriteOptions,
+) -> anyhow::Result<()> {
+    let fallback_after = options.fallback_after.filter(|_| {
+        options.write_type == WriteType::WithResponse
+            && char
+                .properties
+                .contains(CharPropFlags::WRITE_WITHOUT_RESPONSE)
+    });
+    // Name the size when the device refuses it, rather than surfacing the
+    // platform's opaque error
+    let write_error = |e: btleplug::Error| -> anyhow::Error {
+        if OversizedWrite::matches(&e) {
+            OversizedWrite {
+                size: chunk.len(),
+                reason: e.to_string(),
+            }
+            .into()
+        } else {
+            e.into()
+        }
+    };
+    let Some(limit) = fallback_after else {
+        return peripheral
+            .write(char, chunk, options.write_type)
+            .await
+            .map_err(write_error);
+    };
+    match time::timeout(
+        limit,
+        peripheral.write(char, chunk, WriteType::WithResponse),
+    )
+    .await
+    {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(btleplug::Error::TimedOut(_))) | Err(_) => {
+            warn!(
+                "Write to {} not acknowledged within {:?}, retrying without response",
+                char.uuid, limit
+            );
+            peripheral
+                .write(char, chunk, WriteType::WithoutResponse)
+                .await
+                .map_err(write_error)
+        }
+        Ok(Err(e)) => Err(write_error(e)),
+    }
+}
+
+// Characteristic to write: `characteristic` inside the `instance`th service
+// with UUID `service` (see --service-instance)
+#[derive(Clone, Copy)]
+struct CharTarget {
+    service: Uuid,
+    characteristic: Uuid,
+    instance: usize,
+}
+
+// Services are ordered by UUID and contents, so instances are numbered in
+// that order. For instance 0 a characteristic outside the service is still
+// accepted, matching the lookup by characteristic UUID alone.
+fn resolve_characteristic(
+    services: &BTreeSet<Service>,
+    target: CharTarget,
+) -> Option<Characteristic> {
+    let in_instance = services
+        .iter()
+        .filter(|s| s.uuid == target.service)
+        .nth(target.instance)
+        .and_then(|s| {
+            s.characteristics
+                .iter()
+                .find(|c| c.uuid == target.characteristic)
+        });
+    match in_instance {
+        Some(c) => Some(c.clone()),
+        None if target.instance == 0 => services
+            .iter()
+            .flat_map(|s| &s.characteristics)
+            .find(|c| c.uuid == target.characteristic)
+            .cloned(),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    // Parsing reads VIBEKEYS_* variables, so it shares this; a test that sets
+    // one holds it alone
+    static ENVIRONMENT: std::sync::RwLock<()> = std::sync::RwLock::new(());
+
+    fn try_parse(flags: &[&str]) -> Result<Args, clap::Error> {
+        let _env = ENVIRONMENT.read().unwrap_or_else(|e| e.into_inner());
+        Args::try_parse_from(std::iter::once("vibekeys_app").chain(flags.iter().copied()))
+    }
+
+    fn parse(flags: &[&str]) -> Args {
+        try_parse(flags).unwrap()
+    }
+
+    // State for --no-ble, where writes complete without a device
+    fn simulated(flags: &[&str]) -> AppState {
+        let mut argv = vec!["--no-ble"];
+        argv.extend_from_slice(flags);
+        app_state(Arc::new(parse(&argv)), None)
+    }
+
+    // Real BLE mode, but with no adapter and so never a device
+    fn disconnected(flags: &[&str]) -> AppState {
+        app_state(Arc::new(parse(flags)), None)
+    }
+
+    // A controller exposing the display characteristic, not yet connected
+    fn controller(last: u8) -> PlatformPeripheral {
+        let display = characteristic(
+            KEYBOARD_DISPLAY_ID.as_u128(),
+            CONTROLLER_SERVICE_ID.as_u128(),
+            CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE,
+        );
+        PlatformPeripheral::new(
+            addr(last),
+            [service(CONTROLLER_SERVICE_ID.as_u128(), vec![display])],
+        )
+    }
+
+    // State holding `device` as the connected device, with the writer running
+    fn connected(flags: &[&str], device: &PlatformPeripheral) -> AppState {
+        let adapter = Adapter::with(vec![device.clone()]);
+        let state = app_state(Arc::new(parse(flags)), Some(adapter));
+        device.connected.store(true, Ordering::Relaxed);
+        *state.peripheral.try_lock().unwrap() = Some(device.clone());
+        *state.phase.lock().unwrap() = LinkPhase::Connected;
+        tokio::spawn(writer_task(state.clone()));
+        state
+    }
+
+    struct Reply {
+        status: StatusCode,
+        headers: axum::http::HeaderMap,
+        body: serde_json::Value,
+    }
+
+    async fn call(state: &AppState, request: Request<Body>) -> Reply {
+        let response = router(state.clone()).oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&bytes).into_owned().into());
+        Reply {
+            status,
+            headers,
+            body,
+        }
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn post(uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    // Removes the variable when dropped, even if the test fails
+    struct EnvVar(&'static str);
+
+    impl Drop for EnvVar {
+        fn drop(&mut self) {
+            std::env::remove_var(self.0);
+        }
+    }
+
+    #[test]
+    fn environment_fills_in_flags_not_given() {
+        // No other test parses while the variable is set, and it is removed
+        // before they can again
+        let _env = ENVIRONMENT.write().unwrap_or_else(|e| e.into_inner());
+        let var = EnvVar("VIBEKEYS_MAX_PROBES");
+        let parse = |flags: &[&str]| {
+            Args::try_parse_from(std::iter::once("vibekeys_app").chain(flags.iter().copied()))
+                .unwrap()
+        };
+        std::env::set_var(var.0, "9");
+        assert_eq!(parse(&[]).max_probes, 9);
+        assert_eq!(parse(&["--max-probes", "3"]).max_probes, 3);
+        drop(var);
+        assert_eq!(parse(&[]).max_probes, 5);
+    }
+
+    #[tokio::test]
+    async fn simulated_writes_succeed_without_an_adapter() {
+        let state = simulated(&[]);
+        let mut events = state.events.subscribe();
+        let reply = call(
+            &state,
+            post("/send?sync=true", serde_json::json!({ "message": "hi" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body["status"], "ok");
+        assert_eq!(reply.body["simulated"], true);
+        assert!(matches!(
+            events.try_recv(),
+            Ok(Event::Sent { bytes: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn simulated_status_reports_no_device() {
+        let state = simulated(&[]);
+        let reply = call(&state, get("/status")).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body["connected"], false);
+        assert!(state.adapter().is_err());
+    }
+
+    #[test]
+    fn largest_success_stops_at_the_first_failure() {
+        assert_eq!(
+            largest_success(&[(20, true), (100, true), (244, false)]),
+            Some(100)
+        );
+        assert_eq!(
+            largest_success(&[(20, true), (100, false), (244, true)]),
+            Some(20)
+        );
+        assert_eq!(largest_success(&[(20, false)]), None);
+        assert_eq!(largest_success(&[]), None);
+    }
+
+    #[test]
+    fn candidates_rank_by_device_priority_first() {
+        let ranked = rank_candidates(vec![
+            ("unlisted", (None, None, Some(-40))),
+            ("low", (Some(1), None, Some(-90))),
+            ("high", (Some(5), None, Some(-90))),
+        ]);
+        assert_eq!(ranked, ["high", "low", "unlisted"]);
+    }
+
+    #[test]
+    fn most_recently_written_device_is_preferred() {
+        let now = std::time::Instant::now();
+        let earlier = now - Duration::from_secs(60);
+        let ranked = rank_candidates(vec![
+            ("never", (None, None, Some(-40))),
+            ("earlier", (None, Some(earlier), Some(-80))),
+            ("recent", (None, Some(now), Some(-80))),
+        ]);
+        assert_eq!(ranked, ["recent", "earlier", "never"]);
+
+        // RSSI breaks ties, and equal ranks keep the order seen
+        let ranked = rank_candidates(vec![
+            ("first", (None, None, Some(-70))),
+            ("stronger", (None, None, Some(-50))),
+            ("second", (None, None, Some(-70))),
+        ]);
+        assert_eq!(ranked, ["stronger", "first", "second"]);
+    }
+
+    fn addr(last: u8) -> BDAddr {
+        BDAddr::from([0xaa, 0xbb, 0xcc, 0xdd, 0xee, last])
+    }
+
+    #[test]
+    fn previous_device_is_found_by_address_then_by_name() {
+        let seen = [
+            ("other", addr(1), Some("Other".to_string())),
+            ("moved", addr(2), Some("Keys".to_string())),
+            ("same", addr(3), Some("Keys".to_string())),
+        ];
+        let pick = |last_addr, last_name| pick_previous(&seen, last_addr, last_name).map(|s| s.0);
+        assert_eq!(pick(addr(3), Some("Keys")), Some("same"));
+        // A new resolvable private address, so only the name matches
+        assert_eq!(pick(addr(9), Some("Keys")), Some("moved"));
+        assert_eq!(pick(addr(9), None), None);
+        assert_eq!(pick(addr(9), Some("Gone")), None);
+    }
+
+    #[test]
+    fn device_filters_narrow_the_previous_device_candidates() {
+        let args = parse(&[
+            "--device-name",
+            "keys",
+            "--device-address",
+            "AA:BB:CC:DD:EE:02",
+        ]);
+        assert!(device_filter_matches(&args, addr(2), Some("Keys")));
+        assert!(!device_filter_matches(&args, addr(3), Some("Keys")));
+        assert!(!device_filter_matches(&args, addr(2), None));
+        assert!(device_filter_matches(&parse(&[]), addr(3), None));
+    }
+
+    #[test]
+    fn saved_device_must_pass_the_current_filters() {
+        let path = std::env::temp_dir().join(format!("vibekeys-{}-saved.json", std::process::id()));
+        SavedState {
+            last_address: Some("AA:BB:CC:DD:EE:02".to_string()),
+            last_name: Some("Keys".to_string()),
+        }
+        .save(&path)
+        .unwrap();
+        let file = path.to_str().unwrap();
+        let saved = saved_device(&parse(&["--state-file", file]));
+        let filtered = saved_device(&parse(&["--state-file", file, "--device-name", "Other"]));
+        let off = saved_device(&parse(&[]));
+        std::fs::remove_file(&path).ok();
+        assert_eq!(saved.map(|s| s.address), Some(addr(2)));
+        assert!(filtered.is_none());
+        assert!(off.is_none());
+    }
+
+    #[test]
+    fn chunks_split_between_characters() {
+        assert_eq!(split_chunks(b"abcde", 2), [&b"ab"[..], b"cd", b"e"]);
+        // "é" is two bytes and "€" three, so neither is cut in half
+        let text = "aé€".as_bytes();
+        assert_eq!(split_chunks(text, 4), [&text[..3], &text[3..]]);
+        // unless the character is wider than a whole chunk
+        assert_eq!(
+            split_chunks(text, 2),
+            [&text[..1], &text[1..3], &text[3..5], &text[5..]]
+        );
+    }
+
+    #[test]
+    fn binary_data_is_chunked_by_size() {
+        let data = [0x61, 0x80, 0x80, 0xff];
+        assert_eq!(split_chunks(&data, 3), [&data[..3], &data[3..]]);
+    }
+
+    fn characteristic(uuid: u128, service: u128, properties: CharPropFlags) -> Characteristic {
+        Characteristic {
+            uuid: Uuid::from_u128(uuid),
+            service_uuid: Uuid::from_u128(service),
+            properties,
+            descriptors: BTreeSet::new(),
+        }
+    }
+
+    fn service(uuid: u128, characteristics: Vec<Characteristic>) -> Service {
+        Service {
+            uuid: Uuid::from_u128(uuid),
+            primary: true,
+            characteristics: characteristics.into_iter().collect(),
+        }
+    }
+
+    fn target(service: u128, characteristic: u128, instance: usize) -> CharTarget {
+        CharTarget {
+            service: Uuid::from_u128(service),
+            characteristic: Uuid::from_u128(characteristic),
+            instance,
+        }
+    }
+
+    #[test]
+    fn service_instances_are_numbered_in_order() {
+        // Two instances of service 1, told apart by their characteristic
+        let services: BTreeSet<_> = [
+            service(1, vec![characteristic(10, 1, CharPropFlags::READ)]),
+            service(1, vec![characteristic(10, 1, CharPropFlags::WRITE)]),
+        ]
+        .into();
+        let properties = |instance| {
+            resolve_characteristic(&services, target(1, 10, instance)).map(|c| c.properties)
+        };
+        assert_eq!(properties(0), Some(CharPropFlags::READ));
+        assert_eq!(properties(1), Some(CharPropFlags::WRITE));
+        assert_eq!(properties(2), None);
+    }
+
+    #[test]
+    fn only_the_first_instance_looks_outside_the_service() {
+        let services: BTreeSet<_> = [
+            service(1, vec![]),
+            service(1, vec![characteristic(11, 1, CharPropFlags::READ)]),
+            service(2, vec![characteristic(10, 2, CharPropFlags::WRITE)]),
+        ]
+        .into();
+        assert!(resolve_characteristic(&services, target(1, 10, 0)).is_some());
+        assert!(resolve_characteristic(&services, target(1, 10, 1)).is_none());
+    }
+
+    #[test]
+    fn missing_service_and_missing_characteristic_are_told_apart() {
+        let services: BTreeSet<_> = [service(1, vec![])].into();
+        let (present, absent, char) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(10));
+        assert!(missing_char_message(&services, present, char)
+            .contains("check the characteristic UUID"));
+        assert!(missing_char_message(&services, absent, char).contains("not the controller device"));
+    }
+
+    #[test]
+    fn banner_shows_on_terminals_unless_forced() {
+        assert!(show_banner(Banner::Auto, true));
+        assert!(!show_banner(Banner::Auto, false));
+        assert!(show_banner(Banner::Always, false));
+        assert!(!show_banner(Banner::Never, true));
+    }
+
+    #[test]
+    fn banner_lists_enabled_features_by_flag_name() {
+        assert!(enabled_features(&parse(&[])).is_empty());
+        let args = parse(&[
+            "--probe",
+            "--rate-limit",
+            "5",
+            "--response-envelope",
+            "wrapped",
+        ]);
+        assert_eq!(
+            enabled_features(&args),
+            ["probe", "rate-limit", "response-envelope"]
+        );
+    }
+
+    #[test]
+    fn scans_filter_by_service_unless_probing() {
+        let service = parse(&[]).service_uuid;
+        assert_eq!(scan_filter(&parse(&[])).services, [service]);
+        assert!(scan_filter(&parse(&["--probe"])).services.is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_first_attempt_to_succeed_wins() {
+        let attempt = |ms, result| async move {
+            time::sleep(Duration::from_millis(ms)).await;
+            result
+        };
+        let attempts = [attempt(30, Some(1)), attempt(1, None), attempt(10, Some(2))];
+        assert_eq!(first_success(attempts).await, Some(2));
+        assert_eq!(
+            first_success([attempt(1, None::<u8>), attempt(2, None)]).await,
+            None
+        );
+    }
+
+    #[test]
+    fn device_priorities_parse_as_address_equals_priority() {
+        let args = parse(&[
+            "--device-priority",
+            "AA:BB:CC:DD:EE:01=5,AA:BB:CC:DD:EE:02=-1",
+        ]);
+        assert_eq!(args.device_priority, [(addr(1), 5), (addr(2), -1)]);
+        assert!(parse_device_priority("AA:BB:CC:DD:EE:01").is_err());
+        assert!(parse_device_priority("AA:BB:CC:DD:EE:01=high").is_err());
+    }
+
+    #[test]
+    fn device_char_overrides_the_characteristic_per_device() {
+        let uuid = "0000ffe1-0000-1000-8000-00805f9b34fb";
+        let state = simulated(&["--device-char", &format!("AA:BB:CC:DD:EE:01={}", uuid)]);
+        assert_eq!(state.display_char(addr(1)), Uuid::parse_str(uuid).unwrap());
+        assert_eq!(state.display_char(addr(2)), state.args.char_uuid);
+        assert!(parse_device_char("AA:BB:CC:DD:EE:01=not-a-uuid").is_err());
+        assert!(parse_device_char(uuid).is_err());
+    }
+
+    #[test]
+    fn each_connect_reason_has_its_own_greeting() {
+        let state = simulated(&["--greeting-connect", "Hi", "--greeting-reconnect", "Back"]);
+        assert_eq!(state.greeting(ConnectReason::Initial), "Hi");
+        assert_eq!(state.greeting(ConnectReason::Reconnect), "Back");
+        assert_eq!(state.greeting(ConnectReason::Manual), "Connected");
+    }
+
+    #[tokio::test]
+    async fn auth_token_guards_all_but_the_open_paths() {
+        let state = simulated(&["--auth-token", "secret"]);
+        let with_token = |token: &str| {
+            Request::get("/stats")
+                .header("authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert_eq!(call(&state, get("/healthz")).await.status, StatusCode::OK);
+        assert_eq!(
+            call(&state, get("/stats")).await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            call(&state, with_token("guess")).await.status,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            call(&state, with_token("secret")).await.status,
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(tokens_match(b"secret", b"secret"));
+        assert!(!tokens_match(b"secreT", b"secret"));
+        assert!(!tokens_match(b"secret2", b"secret"));
+        assert!(!tokens_match(b"", b"secret"));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_applies_only_to_writes() {
+        let state = simulated(&["--rate-limit", "1"]);
+        let send = || post("/send", serde_json::json!({ "message": "hi" }));
+        assert_eq!(call(&state, send()).await.status, StatusCode::OK);
+        let limited = call(&state, send()).await;
+        assert_eq!(limited.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(limited.body["error"], "rate_limited");
+        assert_eq!(call(&state, get("/stats")).await.status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn responses_carry_the_version_header() {
+        let state = simulated(&[]);
+        let sent = call(
+            &state,
+            post("/send", serde_json::json!({ "message": "hi" })),
+        )
+        .await;
+        assert_eq!(sent.headers["x-vibekeys-version"], short_version());
+        let version = call(&state, get("/version")).await;
+        assert_eq!(version.body["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn schema_lists_the_status_variants() {
+        let reply = call(&simulated(&[]), get("/schema")).await;
+        let schema = reply.body["requests"]["POST /status"].to_string();
+        for variant in ["working", "stopped", "pending"] {
+            assert!(schema.contains(&format!("\"{}\"", variant)), "{}", schema);
+        }
+    }
+
+    #[tokio::test]
+    async fn success_bodies_are_wrapped_only_when_asked() {
+        let flat = call(&simulated(&[]), get("/version")).await;
+        assert_eq!(flat.body["name"], env!("CARGO_PKG_NAME"));
+        assert!(flat.body.get("ok").is_none());
+
+        let state = simulated(&["--response-envelope", "wrapped"]);
+        let wrapped = call(&state, get("/version")).await;
+        assert_eq!(wrapped.body["ok"], true);
+        assert_eq!(wrapped.body["data"]["name"], env!("CARGO_PKG_NAME"));
+        // Errors keep their own shape
+        let error = call(&state, post("/send", serde_json::json!({ "message": "" }))).await;
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(error.body.get("data").is_none());
+    }
+
+    // Simulated writes published so far, oldest first
+    fn writes(events: &mut tokio::sync::broadcast::Receiver<Event>) -> Vec<String> {
+        std::iter::from_fn(|| events.try_recv().ok())
+            .filter_map(|event| match event {
+                Event::Sent { message, .. } => Some(message),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn empty_messages_follow_the_policy() {
+        let empty = || post("/send", serde_json::json!({ "message": "" }));
+
+        let reject = simulated(&[]);
+        assert_eq!(call(&reject, empty()).await.status, StatusCode::BAD_REQUEST);
+
+        let noop = simulated(&["--empty-message", "noop"]);
+        let mut events = noop.events.subscribe();
+        let reply = call(&noop, empty()).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body["written"], false);
+        assert!(writes(&mut events).is_empty());
+
+        let clear = simulated(&["--empty-message", "clear", "--clear-hex", "1b5b324a"]);
+        let mut events = clear.events.subscribe();
+        assert_eq!(call(&clear, empty()).await.status, StatusCode::OK);
+        assert_eq!(writes(&mut events), ["\x1b[2J"]);
+    }
+
+    #[tokio::test]
+    async fn split_on_newline_writes_each_line() {
+        let state = simulated(&["--split-on", "newline"]);
+        let mut events = state.events.subscribe();
+        let reply = call(
+            &state,
+            post(
+                "/send",
+                serde_json::json!({ "message": "one\ntwo\n\nthree" }),
+            ),
+        )
+        .await;
+        assert_eq!(reply.body["lines"], 3);
+        assert_eq!(writes(&mut events), ["one", "two", "three"]);
+    }
+
+    #[tokio::test]
+    async fn typing_writes_each_character_after_the_key_delay() {
+        let state = simulated(&["--key-delay-ms", "20"]);
+        let mut events = state.events.subscribe();
+        let started = std::time::Instant::now();
+        // "e" followed by a combining accent is one character on screen
+        let reply = call(
+            &state,
+            post("/type", serde_json::json!({ "text": "ne\u{301}w" })),
+        )
+        .await;
+        assert_eq!(reply.body["writes"], 3);
+        assert_eq!(writes(&mut events), ["n", "e\u{301}", "w"]);
+        assert!(started.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[tokio::test]
+    async fn batch_elements_keep_their_own_write_type() {
+        let messages: Vec<BatchMessage> = serde_json::from_value(serde_json::json!([
+            { "message": "a", "write_type": "with-response" },
+            { "message": "b" },
+            { "message": "c", "write_type": "without-response" },
+        ]))
+        .unwrap();
+        let types: Vec<_> = batch_parts(&messages, WriteKind::WithoutResponse)
+            .into_iter()
+            .map(|(_, write_type)| write_type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                WriteType::WithResponse,
+                WriteType::WithoutResponse,
+                WriteType::WithoutResponse
+            ]
+        );
+
+        let state = simulated(&[]);
+        let invalid = serde_json::json!({ "messages": [{ "message": "a", "write_type": "fast" }] });
+        assert!(call(&state, post("/send/batch", invalid))
+            .await
+            .status
+            .is_client_error());
+        let valid = serde_json::json!({ "messages": [{ "message": "a" }, { "message": "b" }] });
+        assert_eq!(
+            call(&state, post("/send/batch", valid)).await.body["count"],
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn raw_statuses_are_framed_and_checked() {
+        let state = simulated(&["--raw-status-allow", "charging,idle"]);
+        let mut events = state.events.subscribe();
+        let raw = |status: &str| post("/status/raw", serde_json::json!({ "status": status }));
+        assert_eq!(call(&state, raw(" charging ")).await.status, StatusCode::OK);
+        assert_eq!(writes(&mut events), ["[charging]"]);
+        assert_eq!(
+            state.last_status.lock().unwrap().as_deref(),
+            Some("[charging]")
+        );
+
+        for refused in [
+            "",
+            "sleeping",
+            "[idle]",
+            &"x".repeat(MAX_RAW_STATUS_LEN + 1),
+        ] {
+            let reply = call(&state, raw(refused)).await;
+            assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{:?}", refused);
+        }
+        assert!(writes(&mut events).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reconnects_restore_the_last_status() {
+        let state = simulated(&[
+            "--restore-status-on-connect",
+            "--greeting-reconnect",
+            "Back",
+        ]);
+        assert_eq!(state.connect_message(ConnectReason::Reconnect), "Back");
+        let status = post("/status", serde_json::json!({ "status": "working" }));
+        assert_eq!(call(&state, status).await.status, StatusCode::OK);
+        assert_eq!(state.connect_message(ConnectReason::Reconnect), "[working]");
+
+        // Without the flag the greeting is always shown
+        let state = simulated(&["--greeting-reconnect", "Back"]);
+        *state.last_status.lock().unwrap() = Some("[working]".to_string());
+        assert_eq!(state.connect_message(ConnectReason::Reconnect), "Back");
+    }
+
+    #[tokio::test]
+    async fn unchanged_statuses_are_skipped_until_the_display_changes() {
+        let state = simulated(&["--status-on-change"]);
+        let status = || post("/status", serde_json::json!({ "status": "working" }));
+        assert_ne!(call(&state, status()).await.body["written"], false);
+        assert_eq!(call(&state, status()).await.body["written"], false);
+
+        // Anything else written replaces the status on the display
+        let send = post("/send", serde_json::json!({ "message": "hi" }));
+        assert_eq!(call(&state, send).await.status, StatusCode::OK);
+        assert_ne!(call(&state, status()).await.body["written"], false);
+    }
+
+    #[tokio::test]
+    async fn send_key_accepts_only_named_keys() {
+        let state = simulated(&[]);
+        let mut events = state.events.subscribe();
+        let key = |uri, key: &str| {
+            post(
+                uri,
+                serde_json::json!({ "key": key, "modifiers": ["ctrl"] }),
+            )
+        };
+        assert_eq!(
+            call(&state, key("/send/key", "c")).await.status,
+            StatusCode::BAD_REQUEST
+        );
+        let unknown = call(&state, key("/send/key", "nope")).await;
+        assert_eq!(unknown.body["error"], "unknown_key");
+        assert!(unknown.body["supported"]
+            .as_array()
+            .unwrap()
+            .contains(&"enter".into()));
+        assert!(writes(&mut events).is_empty());
+
+        // /key still falls back to single characters
+        assert_eq!(call(&state, key("/key", "c")).await.status, StatusCode::OK);
+        assert_eq!(writes(&mut events), ["\x03"]);
+    }
+
+    #[tokio::test]
+    async fn the_last_writing_session_is_reported() {
+        let state = simulated(&[]);
+        let session = call(&state, post("/session", serde_json::json!({})))
+            .await
+            .body["session"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let request = Request::post("/send")
+            .header("content-type", "application/json")
+            .header("x-session", &session)
+            .body(Body::from(r#"{"message":"hi"}"#))
+            .unwrap();
+        assert_eq!(call(&state, request).await.status, StatusCode::OK);
+        assert_eq!(
+            call(&state, get("/session")).await.body["last_writer"],
+            session.as_str()
+        );
+
+        // A write without a session clears the attribution
+        call(
+            &state,
+            post("/send", serde_json::json!({ "message": "bye" })),
+        )
+        .await;
+        assert!(call(&state, get("/session")).await.body["last_writer"].is_null());
+    }
+
+    #[tokio::test]
+    async fn cors_allows_only_the_configured_origins() {
+        assert!(cors_layer(&[]).unwrap().is_none());
+        assert!(cors_layer(&["not a\norigin".to_string()]).is_err());
+
+        let cors = cors_layer(&["http://localhost:3000/".to_string()])
+            .unwrap()
+            .unwrap();
+        let app = router(simulated(&[])).layer(cors);
+        let preflight = |origin: &str| {
+            Request::options("/send")
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", "content-type")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let allowed = app
+            .clone()
+            .oneshot(preflight("http://localhost:3000"))
+            .await
+            .unwrap();
+        assert_eq!(
+            allowed.headers()["access-control-allow-origin"],
+            "http://localhost:3000"
+        );
+        let other = app.oneshot(preflight("http://example.com")).await.unwrap();
+        assert!(other.headers().get("access-control-allow-origin").is_none());
+    }
+
+    #[tokio::test]
+    async fn healthz_answers_without_a_token_or_device() {
+        let state = simulated(&["--auth-token", "secret"]);
+        let reply = call(&state, get("/healthz")).await;
+        assert_eq!(reply.status, StatusCode::OK);
+        assert_eq!(reply.body, "ok");
+    }
+
+    #[test]
+    fn reset_state_removes_the_state_file() {
+        let path = std::env::temp_dir().join(format!("vibekeys-{}-reset.json", std::process::id()));
+        SavedState::default().save(&path).unwrap();
+        reset_state(&path).unwrap();
+        assert!(!path.exists());
+        // Already gone is not an error
+        reset_state(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn port_file_holds_the_bound_port() {
+        let path = std::env::temp_dir().join(format!("vibekeys-{}-port", std::process::id()));
+        let file = path.to_str().unwrap();
+        let listener = bind(&parse(&[
+            "--host",
+            "127.0.0.1",
+            "--port",
+            "0",
+            "--port-file",
+            file,
+        ]))
+        .await
+        .unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        let port = listener.local_addr().unwrap().port();
+        assert_ne!(port, 0);
+        assert_eq!(written, format!("{}\n", port));
+    }
+
+    #[tokio::test]
+    async fn writes_and_reads_without_a_device_use_the_configured_code() {
+        let state = disconnected(&["--no-device-status", "425"]);
+        let requests = [
+            post("/send", serde_json::json!({ "message": "hi" })),
+            post("/status", serde_json::json!({ "status": "working" })),
+            post("/read/batch", serde_json::json!({ "characteristics": [] })),
+        ];
+        for request in requests {
+            let reply = call(&state, request).await;
+            assert_eq!(reply.status, StatusCode::TOO_EARLY, "{}", reply.body);
+            assert_eq!(reply.body["error"], "no_device");
+        }
+        let default = disconnected(&[]);
+        let reply = call(
+            &default,
+            post("/send", serde_json::json!({ "message": "hi" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn sends_while_disconnected_are_buffered_dropping_the_oldest() {
+        let flags = [
+            "--buffer-while-disconnected",
+            "--disconnected-buffer-size",
+            "2",
+        ];
+        let state = disconnected(&flags);
+        for (message, held) in [("one", 1), ("two", 2), ("three", 2)] {
+            let reply = call(
+                &state,
+                post("/send", serde_json::json!({ "message": message })),
+            )
+            .await;
+            assert_eq!(reply.status, StatusCode::ACCEPTED);
+            assert_eq!(reply.body["buffered"], held);
+        }
+        let buffered: Vec<_> = state
+            .offline
+            .as_ref()
+            .unwrap()
+            .drain()
+            .into_iter()
+            .map(|write| write.parts)
+            .collect();
+        assert_eq!(buffered, [[b"two".to_vec()], [b"three".to_vec()]]);
+
+        // Not held after a requested disconnect, since no reconnect follows
+        state.user_disconnected.store(true, Ordering::Relaxed);
+        let reply = call(
+            &state,
+            post("/send", serde_json::json!({ "message": "four" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn writes_wait_for_a_free_in_flight_slot() {
+        let state = disconnected(&["--max-inflight-writes", "1"]);
+        tokio::spawn(writer_task(state.clone()));
+        let slot = state.write_slots.clone().acquire_owned().await.unwrap();
+        let group = state.write_queue.next_group();
+        let mut done = enqueue_write(
+            &state,
+            b"hi",
+            WriteType::WithoutResponse,
+            Priority::Normal,
+            group,
+        )
+        .ok()
+        .unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(done.try_recv().is_err());
+
+        // With the slot free the write goes ahead, and fails for want of a device
+        drop(slot);
+        let result = time::timeout(Duration::from_secs(1), await_write(done))
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(ApiError::NoDevice(_))));
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_queued_and_in_flight_writes() {
+        let state = disconnected(&[]);
+        let group = state.write_queue.next_group();
+        let pending: Vec<_> = ["one", "two"]
+            .into_iter()
+            .map(|m| {
+                enqueue_write(
+                    &state,
+                    m.as_bytes(),
+                    WriteType::WithResponse,
+                    Priority::Normal,
+                    group,
+                )
+                .ok()
+                .unwrap()
+            })
+            .collect();
+        let wait = Duration::from_millis(100);
+        assert!(time::timeout(wait, writes_drained(&state)).await.is_err());
+
+        tokio::spawn(writer_task(state.clone()));
+        time::timeout(Duration::from_secs(1), writes_drained(&state))
+            .await
+            .unwrap();
+        for done in pending {
+            assert!(time::timeout(wait, done).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn ble_operations_run_one_at_a_time() {
+        let state = simulated(&["--max-ble-ops", "1"]);
+        let first = state.ble_op().await;
+        assert!(time::timeout(Duration::from_millis(50), state.ble_op())
+            .await
+            .is_err());
+        drop(first);
+        assert!(time::timeout(Duration::from_millis(50), state.ble_op())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn write_config_reports_the_effective_chunk_size() {
+        let state = simulated(&["--chunk-size", "20", "--auto-mtu"]);
+        let reply = call(&state, get("/write-config")).await;
+        assert_eq!(reply.body["chunk_size"], 20);
+
+        // As set by the --auto-mtu probe on connect
+        state.chunk_size.store(182, Ordering::Relaxed);
+        let reply = call(&state, get("/write-config")).await;
+        assert_eq!(reply.body["chunk_size"], 182);
+        assert_eq!(reply.body["configured_chunk_size"], 20);
+        assert_eq!(reply.body["auto_mtu"], true);
+    }
+
+    #[tokio::test]
+    async fn health_summaries_count_since_the_previous_one() {
+        let state = simulated(&["--health-log-secs", "60"]);
+        let mut baseline = state.stats.snapshot();
+        state.stats.write_ok();
+        state.stats.write_failed();
+        state.stats.expired();
+        assert_eq!(health_counts(&state.stats.snapshot(), &baseline), (1, 2));
+        log_health(&state, &mut baseline).await;
+        assert_eq!(health_counts(&state.stats.snapshot(), &baseline), (0, 0));
+
+        // Without --health-log-secs there is never a summary to log
+        let mut off = None;
+        assert!(
+            time::timeout(Duration::from_millis(20), next_tick(&mut off))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_lost_link_is_reported_as_disconnected() {
+        let state = simulated(&[]);
+        *state.phase.lock().unwrap() = LinkPhase::Connected;
+        link_lost(&state);
+        assert_eq!(*state.phase.lock().unwrap(), LinkPhase::Disconnected);
+        assert_eq!(
+            call(&state, get("/status")).await.body["phase"],
+            "disconnected"
+        );
+        // The monitor is woken to reconnect straight away
+        let woken = time::timeout(Duration::from_millis(50), state.link_check.notified());
+        assert!(woken.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn tx_power_can_be_read_but_not_set() {
+        let state = disconnected(&[]);
+        let set = call(&state, post("/tx-power", serde_json::json!({ "level": 4 }))).await;
+        assert_eq!(set.status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(set.body["error"], "unsupported");
+        let get = call(&state, get("/tx-power")).await;
+        assert_eq!(get.body["error"], "no_device");
+    }
+
+    #[tokio::test]
+    async fn disconnect_without_a_device_is_a_conflict() {
+        let state = simulated(&[]);
+        let reply = call(&state, post("/disconnect", serde_json::json!({}))).await;
+        assert_eq!(reply.status, StatusCode::CONFLICT);
+        assert!(!state.user_disconnected.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn waits_end_early_on_shutdown() {
+        let (stop, mut stopped) = tokio::sync::watch::channel(false);
+        assert!(!sleep_or_stop(Duration::from_millis(1), &mut stopped).await);
+        stop.send(true).unwrap();
+        let wait = sleep_or_stop(Duration::from_secs(60), &mut stopped);
+        assert!(time::timeout(Duration::from_secs(1), wait).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn raw_sends_decode_hex_or_base64() {
+        let state = simulated(&[]);
+        let mut events = state.events.subscribe();
+        let raw = |body| post("/send/raw", body);
+        assert_eq!(
+            call(&state, raw(serde_json::json!({ "hex": "1b 5b" })))
+                .await
+                .status,
+            StatusCode::OK
+        );
+        assert_eq!(
+            call(&state, raw(serde_json::json!({ "base64": "G1s=" })))
+                .await
+                .status,
+            StatusCode::OK
+        );
+        assert_eq!(writes(&mut events), ["\x1b[", "\x1b["]);
+        let reply = call(&state, raw(serde_json::json!({ "hex": "00" }))).await;
+        assert_eq!(reply.body["simulated"], true);
+
+        for refused in [
+            serde_json::json!({ "hex": "" }),
+            serde_json::json!({ "hex": "zz" }),
+            serde_json::json!({ "base64": "not base64!" }),
+            serde_json::json!({ "hex": "00", "base64": "AA==" }),
+            serde_json::json!({}),
+        ] {
+            let reply = call(&state, raw(refused.clone())).await;
+            assert_eq!(reply.status, StatusCode::BAD_REQUEST, "{}", refused);
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_are_refused_before_writing() {
+        let state = simulated(&["--max-message-bytes", "4"]);
+        let mut events = state.events.subscribe();
+        // Six bytes in three characters: the limit counts bytes
+        let reply = call(
+            &state,
+            post("/send", serde_json::json!({ "message": "aé€" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(reply.body["size"], 6);
+        assert_eq!(reply.body["limit"], 4);
+        assert!(writes(&mut events).is_empty());
+        let reply = call(
+            &state,
+            post("/send", serde_json::json!({ "message": "abcd" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK);
+    }
+
+    #[test]
+    fn service_and_characteristic_uuids_can_be_overridden() {
+        let defaults = parse(&[]);
+        assert_eq!(defaults.service_uuid, CONTROLLER_SERVICE_ID);
+        assert_eq!(defaults.char_uuid, KEYBOARD_DISPLAY_ID);
+
+        let (service, char) = (
+            "0000ffe0-0000-1000-8000-00805f9b34fb",
+            "0000ffe1-0000-1000-8000-00805f9b34fb",
+        );
+        let state = simulated(&["--service-uuid", service, "--char-uuid", char]);
+        let target = state.display_target(addr(1));
+        assert_eq!(target.service, Uuid::parse_str(service).unwrap());
+        assert_eq!(target.characteristic, Uuid::parse_str(char).unwrap());
+        assert!(try_parse(&["--char-uuid", "ffe1"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn per_request_write_types_are_validated() {
+        let state = simulated(&[]);
+        let send = |write_type: &str| {
+            post(
+                "/send",
+                serde_json::json!({ "message": "hi", "write_type": write_type }),
+            )
+        };
+        assert_eq!(
+            call(&state, send("without-response")).await.status,
+            StatusCode::OK
+        );
+        assert!(call(&state, send("sometimes"))
+            .await
+            .status
+            .is_client_error());
+    }
+
+    #[tokio::test]
+    async fn events_drains_the_buffered_notifications() {
+        let state = simulated(&[]);
+        state
+            .notifications
+            .push(Notification::new(Uuid::from_u128(1), b"a"));
+        state
+            .notifications
+            .push(Notification::new(Uuid::from_u128(1), b"b"));
+        let reply = call(&state, get("/events")).await;
+        let values: Vec<_> = reply
+            .body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|n| n["value"].clone())
+            .collect();
+        assert_eq!(values, ["a", "b"]);
+        assert_eq!(
+            call(&state, get("/events")).await.body,
+            serde_json::json!([])
+        );
+    }
+
+    #[tokio::test]
+    async fn device_without_a_device_is_no_device() {
+        let state = disconnected(&[]);
+        let reply = call(&state, get("/device")).await;
+        assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(reply.body["error"], "no_device");
+    }
+
+    #[tokio::test]
+    async fn reconnect_needs_an_adapter() {
+        let reply = call(&simulated(&[]), post("/reconnect", serde_json::json!({}))).await;
+        assert_eq!(reply.status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(reply.body["error"], "unsupported");
+    }
+
+    #[tokio::test]
+    async fn not_ready_without_a_device() {
+        let state = simulated(&[]);
+        for uri in ["/ready", "/ready?probe=true"] {
+            let reply = call(&state, get(uri)).await;
+            assert_eq!(reply.status, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(reply.body["error"], "unavailable");
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_fail_up_front_without_a_device() {
+        let state = disconnected(&["--verify-before-write"]);
+        let reply = call(
+            &state,
+            post("/send", serde_json::json!({ "message": "hi" })),
+        )
+        .await;
+        assert_eq!(reply.body["error"], "no_device");
+        let raw = call(
+            &state,
+            post("/send/raw", serde_json::json!({ "hex": "00" })),
+        )
+        .await;
+        assert_eq!(raw.body["error"], "no_device");
+        // Refused before reaching the queue
+        assert_eq!(state.write_queue.depth(), 0);
+        assert_eq!(state.stats.snapshot().writes_failed, 0);
+    }
+
+    #[test]
+    fn reconnect_waits_double_up_to_the_cap() {
+        let max = Duration::from_secs(60);
+        let waits: Vec<_> = std::iter::successors(Some(Duration::from_secs(5)), |&b| {
+            Some(next_backoff(b, max))
+        })
+        .take(6)
+        .map(|b| b.as_secs())
+        .collect();
+        assert_eq!(waits, [5, 10, 20, 40, 60, 60]);
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_writes_fall_back_to_without_response() {
+        let device = controller(1);
+        device.unacknowledged.store(true, Ordering::Relaxed);
+        let state = connected(
+            &["--fallback-write-type", "--write-timeout-ms", "20"],
+            &device,
+        );
+        let reply = call(
+            &state,
+            post("/send?sync=true", serde_json::json!({ "message": "hi" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        let ops = device.ops.lock().unwrap().clone();
+        assert!(
+            matches!(
+                &ops[..],
+                [mock_ble::Op::Write { data, write_type: WriteType::WithoutResponse, .. }] if data == b"hi"
+            ),
+            "{:?}",
+            ops
+        );
+    }
+
+    #[tokio::test]
+    async fn a_send_that_failed_is_not_collapsed_on_retry() {
+        let device = controller(1);
+        let state = connected(&["--collapse-repeats"], &device);
+        let held = state.peripheral.lock().await.take();
+        *state.phase.lock().unwrap() = LinkPhase::Disconnected;
+        let send = || post("/send?sync=true", serde_json::json!({ "message": "hi" }));
+        let reply = call(&state, send()).await;
+        assert_eq!(
+            reply.status,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "{}",
+            reply.body
+        );
+
+        *state.peripheral.lock().await = held;
+        *state.phase.lock().unwrap() = LinkPhase::Connected;
+        let reply = call(&state, send()).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        assert_eq!(reply.body.get("collapsed"), None, "{}", reply.body);
+        let ops = device.ops.lock().unwrap().clone();
+        assert!(
+            matches!(&ops[..], [mock_ble::Op::Write { data, .. }] if data == b"hi"),
+            "{:?}",
+            ops
+        );
+
+        // Now written, so the same message again is a repeat
+        let reply = call(&state, send()).await;
+        assert_eq!(reply.body["collapsed"], true, "{}", reply.body);
+    }
+
+    fn notification(uuid: u128, value: &[u8]) -> ValueNotification {
+        ValueNotification {
+            uuid: Uuid::from_u128(uuid),
+            value: value.to_vec(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn await_echo_compares_the_collected_notifications() {
+        let timeout = Duration::from_secs(1);
+        let uuid = Uuid::from_u128(0xE);
+        // Split across chunks, with another characteristic's notification between
+        let chunks = vec![
+            notification(0xE, b"he"),
+            notification(0xF, b"xx"),
+            notification(0xE, b"llo"),
+        ];
+        assert!(await_echo(futures::stream::iter(chunks), uuid, b"hello", timeout).await);
+        let mismatched = vec![notification(0xE, b"help!")];
+        assert!(!await_echo(futures::stream::iter(mismatched), uuid, b"hello", timeout).await);
+        // The device stops echoing halfway
+        let partial =
+            futures::stream::iter(vec![notification(0xE, b"he")]).chain(futures::stream::pending());
+        let started = time::Instant::now();
+        assert!(!await_echo(partial, uuid, b"hello", timeout).await);
+        assert_eq!(started.elapsed(), timeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn await_sequence_skips_other_confirmations() {
+        let timeout = Duration::from_secs(1);
+        let uuid = Uuid::from_u128(0xE);
+        let confirmations = vec![
+            notification(0xE, &[1, 0]),
+            notification(0xF, &[2]),
+            notification(0xE, &[2, 0]),
+        ];
+        assert!(await_sequence(futures::stream::iter(confirmations), uuid, &[2], timeout).await);
+        let others = vec![notification(0xE, &[1]), notification(0xF, &[2])];
+        assert!(!await_sequence(futures::stream::iter(others), uuid, &[2], timeout).await);
+        let silent = futures::stream::pending();
+        let started = time::Instant::now();
+        assert!(!await_sequence(silent, uuid, &[2], timeout).await);
+        assert_eq!(started.elapsed(), timeout);
+    }
+
+    #[tokio::test]
+    async fn the_disconnect_message_is_the_last_write_before_disconnecting() {
+        let device = controller(1);
+        let state = connected(&["--disconnect-message", "bye"], &device);
+        let reply = call(
+            &state,
+            post("/send?sync=true", serde_json::json!({ "message": "hi" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        let reply = call(&state, post("/disconnect", serde_json::json!({}))).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+
+        let ops = device.ops.lock().unwrap().clone();
+        assert!(
+            matches!(
+                &ops[..],
+                [
+                    mock_ble::Op::Write { data: first, .. },
+                    mock_ble::Op::Write { data: last, .. },
+                    mock_ble::Op::Disconnect,
+                ] if first == b"hi" && last == b"bye"
+            ),
+            "{:?}",
+            ops
+        );
+        assert!(state.peripheral.lock().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn require_char_rejects_a_device_without_the_display_characteristic() {
+        // The controller service, but nothing to write to in it
+        let bare =
+            || PlatformPeripheral::new(addr(2), [service(CONTROLLER_SERVICE_ID.as_u128(), vec![])]);
+
+        let lenient = bare();
+        let state = disconnected(&[]);
+        connect_and_discover(&lenient, &state, ConnectReason::Initial)
+            .await
+            .unwrap();
+        assert!(lenient.is_connected().await.unwrap());
+
+        let strict = bare();
+        let state = disconnected(&["--require-char"]);
+        let error = connect_and_discover(&strict, &state, ConnectReason::Initial)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains(&KEYBOARD_DISPLAY_ID.to_string()),
+            "{}",
+            error
+        );
+        assert!(!strict.is_connected().await.unwrap());
+        assert_eq!(
+            strict.ops.lock().unwrap().last(),
+            Some(&mock_ble::Op::Disconnect)
+        );
+
+        let device = controller(1);
+        connect_and_discover(&device, &state, ConnectReason::Initial)
+            .await
+            .unwrap();
+        assert!(device.is_connected().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn probing_skips_other_devices_up_to_max_probes() {
+        let other = |last| PlatformPeripheral::new(addr(last), [service(0xF00D, vec![])]);
+        let devices = [other(1), other(2), controller(3)];
+        let ops = |device: &PlatformPeripheral| device.ops.lock().unwrap().clone();
+
+        assert!(probe_peripherals(&devices, CONTROLLER_SERVICE_ID, 2)
+            .await
+            .is_none());
+        for probed in &devices[..2] {
+            assert_eq!(
+                ops(probed),
+                [mock_ble::Op::Connect, mock_ble::Op::Disconnect]
+            );
+        }
+        assert_eq!(ops(&devices[2]), []);
+
+        let found = probe_peripherals(&devices, CONTROLLER_SERVICE_ID, 3)
+            .await
+            .unwrap();
+        assert_eq!(found.address(), addr(3));
+        // Left connected, the others disconnected again
+        assert_eq!(ops(&devices[2]), [mock_ble::Op::Connect]);
+        assert!(!devices[0].is_connected().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn batch_reads_report_each_characteristic_in_its_own_entry() {
+        let service_id = CONTROLLER_SERVICE_ID.as_u128();
+        let readable = characteristic(0xA1, service_id, CharPropFlags::READ);
+        let display = characteristic(
+            KEYBOARD_DISPLAY_ID.as_u128(),
+            service_id,
+            CharPropFlags::WRITE,
+        );
+        let device =
+            PlatformPeripheral::new(addr(1), [service(service_id, vec![readable, display])]);
+        device
+            .values
+            .lock()
+            .unwrap()
+            .insert(Uuid::from_u128(0xA1), b"ok".to_vec());
+        let state = connected(&[], &device);
+
+        let uuids = [
+            Uuid::from_u128(0xA1),
+            KEYBOARD_DISPLAY_ID,
+            Uuid::from_u128(0xA2),
+        ];
+        let reply = call(
+            &state,
+            post(
+                "/read/batch",
+                serde_json::json!({ "characteristics": uuids }),
+            ),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        let results = reply.body["results"].as_array().unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0]["value"], "ok");
+        assert_eq!(results[0]["hex"], "6f6b");
+        assert_eq!(results[1]["error"], "unsupported");
+        assert_eq!(results[2]["error"], "characteristic_not_found");
+        for (result, uuid) in results.iter().zip(uuids) {
+            assert_eq!(result["uuid"], uuid.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn unsubscribing_removes_the_subscription_once() {
+        let service_id = CONTROLLER_SERVICE_ID.as_u128();
+        let display = characteristic(
+            KEYBOARD_DISPLAY_ID.as_u128(),
+            service_id,
+            CharPropFlags::WRITE,
+        );
+        let status = characteristic(0xB1, service_id, CharPropFlags::NOTIFY);
+        let device = PlatformPeripheral::new(addr(1), [service(service_id, vec![display, status])]);
+        let state = connected(&[], &device);
+        let uuid = Uuid::from_u128(0xB1);
+        let request = |path| post(path, serde_json::json!({ "characteristic": uuid }));
+
+        let reply = call(&state, request("/subscribe")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        assert_eq!(reply.body["subscriptions"], serde_json::json!([uuid]));
+        let reply = call(&state, request("/unsubscribe")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        assert_eq!(reply.body["subscriptions"], serde_json::json!([]));
+        let reply = call(&state, request("/unsubscribe")).await;
+        assert_eq!(reply.status, StatusCode::CONFLICT, "{}", reply.body);
+
+        assert_eq!(
+            *device.ops.lock().unwrap(),
+            [
+                mock_ble::Op::Subscribe(uuid),
+                mock_ble::Op::Unsubscribe(uuid)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_second_scan_waits_for_the_first_to_stop() {
+        let adapter = Adapter::default();
+        let state = app_state(Arc::new(parse(&[])), Some(adapter.clone()));
+        let first = Scan::start(&state, &adapter, ScanFilter::default())
+            .await
+            .unwrap();
+
+        let second = tokio::spawn({
+            let (state, adapter) = (state.clone(), adapter.clone());
+            async move {
+                let scan = Scan::start(&state, &adapter, ScanFilter::default()).await;
+                scan.unwrap().stop(&adapter).await;
+            }
+        });
+        time::sleep(Duration::from_secs(10)).await;
+        assert!(!second.is_finished());
+        assert_eq!(adapter.scans_started.load(Ordering::Relaxed), 1);
+
+        first.stop(&adapter).await;
+        second.await.unwrap();
+        assert_eq!(adapter.scans_started.load(Ordering::Relaxed), 2);
+        assert_eq!(adapter.scans_stopped.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_scan_stop_does_not_end_the_startup_loop() {
+        let device = controller(1);
+        let adapter = Adapter::with(vec![device.clone()]);
+        adapter.stop_scan_fails.store(true, Ordering::Relaxed);
+        // Nothing seen in the first round, so a second scan is needed
+        adapter.empty_listings.store(1, Ordering::Relaxed);
+        let args = parse(&["--peripherals-retries", "0"]);
+        let state = app_state(Arc::new(args), Some(adapter.clone()));
+        let (_stop, stopped) = tokio::sync::watch::channel(false);
+
+        let found = connect_at_startup(&state, stopped).await.unwrap();
+        assert_eq!(found.address(), device.address());
+        assert_eq!(adapter.scans_started.load(Ordering::Relaxed), 2);
+        assert_eq!(adapter.scans_stopped.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn listing_is_retried_while_empty_or_failing() {
+        let device = controller(1);
+        let adapter = Adapter::with(vec![device.clone()]);
+        let state = app_state(
+            Arc::new(parse(&["--peripherals-retries", "3"])),
+            Some(adapter.clone()),
+        );
+        adapter.failed_listings.store(1, Ordering::Relaxed);
+        adapter.empty_listings.store(2, Ordering::Relaxed);
+        let started = time::Instant::now();
+        let listed = list_peripherals(&state).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(started.elapsed(), Duration::from_millis(3 * 500));
+
+        // Out of retries, the last answer stands
+        adapter.empty_listings.store(4, Ordering::Relaxed);
+        assert!(list_peripherals(&state).await.unwrap().is_empty());
+        adapter.failed_listings.store(4, Ordering::Relaxed);
+        assert!(list_peripherals(&state).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_powered_off_adapter_is_powered_on_or_reported() {
+        use tokio::process::Command;
+        let adapter = |states: &[CentralState]| {
+            let adapter = Adapter::default();
+            adapter
+                .states
+                .lock()
+                .unwrap()
+                .extend(states.iter().cloned());
+            adapter
+        };
+        // Never run while the adapter is on
+        let missing = || Command::new("vibekeys-no-such-tool");
+        ensure_powered_on_with(&adapter(&[CentralState::PoweredOn]), missing())
+            .await
+            .unwrap();
+
+        let off_then_on = adapter(&[CentralState::PoweredOff, CentralState::PoweredOn]);
+        ensure_powered_on_with(&off_then_on, Command::new("true"))
+            .await
+            .unwrap();
+
+        let error =
+            ensure_powered_on_with(&adapter(&[CentralState::PoweredOff]), Command::new("true"))
+                .await
+                .unwrap_err();
+        assert!(error.to_string().contains("still powered off"), "{}", error);
+        let error =
+            ensure_powered_on_with(&adapter(&[CentralState::PoweredOff]), Command::new("false"))
+                .await
+                .unwrap_err();
+        assert!(
+            error.to_string().contains("could not be powered on"),
+            "{}",
+            error
+        );
+        let error = ensure_powered_on_with(&adapter(&[CentralState::PoweredOff]), missing())
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("bluetoothctl is unavailable"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn adapter_events_are_only_subscribed_to_with_trace_ble() {
+        let adapter = Adapter::default();
+        start_ble_trace(&parse(&[]), &adapter).await.unwrap();
+        assert_eq!(adapter.event_streams.load(Ordering::Relaxed), 0);
+        start_ble_trace(&parse(&["--trace-ble"]), &adapter)
+            .await
+            .unwrap();
+        assert_eq!(adapter.event_streams.load(Ordering::Relaxed), 1);
+    }
+
+    // State with the monitor running on `adapter`, once it has connected to
+    // a device at startup. Dropping the sender stops the monitor.
+    async fn monitored(
+        flags: &[&str],
+        adapter: &Adapter,
+    ) -> (AppState, tokio::sync::watch::Sender<bool>) {
+        let state = app_state(Arc::new(parse(flags)), Some(adapter.clone()));
+        let (stop, stopped) = tokio::sync::watch::channel(false);
+        tokio::spawn(ble_monitor_task(state.clone(), stopped));
+        while state.peripheral.lock().await.is_none() {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        (state, stop)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_interrupts_the_backoff_and_reports_failure() {
+        let device = controller(1);
+        let adapter = Adapter::with(vec![device.clone()]);
+        let (state, _stop) = monitored(&[], &adapter).await;
+        let reconnect = || post("/reconnect", serde_json::json!({}));
+
+        // The device goes away, so the monitor backs off up to its cap
+        adapter.peripherals.lock().unwrap().clear();
+        device.disconnect().await.unwrap();
+        time::sleep(Duration::from_secs(300)).await;
+
+        // A round for the request that finds nothing answers straight away
+        let started = time::Instant::now();
+        let reply = call(&state, reconnect()).await;
+        assert_eq!(
+            reply.status,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "{}",
+            reply.body
+        );
+        assert!(
+            reply.body["message"]
+                .as_str()
+                .unwrap()
+                .contains("not found"),
+            "{}",
+            reply.body
+        );
+        assert!(started.elapsed() < Duration::from_secs(15));
+
+        // Back at the cap, a request retries now rather than after it
+        time::sleep(Duration::from_secs(300)).await;
+        adapter.peripherals.lock().unwrap().push(device.clone());
+        let started = time::Instant::now();
+        let reply = call(&state, reconnect()).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        assert_eq!(reply.body["ready"], true);
+        assert!(started.elapsed() < Duration::from_secs(15));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnect_answers_for_a_device_without_the_display_characteristic() {
+        let bare =
+            PlatformPeripheral::new(addr(2), [service(CONTROLLER_SERVICE_ID.as_u128(), vec![])]);
+        let adapter = Adapter::with(vec![bare.clone()]);
+        let (state, _stop) = monitored(&[], &adapter).await;
+        let reply = call(&state, post("/reconnect", serde_json::json!({}))).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        assert_eq!(reply.body["address"], bare.address().to_string());
+        assert_eq!(reply.body["ready"], false);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connecting_looks_for_the_display_in_the_service_instance() {
+        let service_id = CONTROLLER_SERVICE_ID.as_u128();
+        let display = || {
+            characteristic(
+                KEYBOARD_DISPLAY_ID.as_u128(),
+                service_id,
+                CharPropFlags::WRITE,
+            )
+        };
+        let other = characteristic(0xC1, service_id, CharPropFlags::READ);
+        // Two instances of the controller service
+        let device = || {
+            PlatformPeripheral::new(
+                addr(1),
+                [
+                    service(service_id, vec![display()]),
+                    service(service_id, vec![display(), other.clone()]),
+                ],
+            )
+        };
+
+        let second = disconnected(&["--require-char", "--service-instance", "1"]);
+        connect_and_discover(&device(), &second, ConnectReason::Initial)
+            .await
+            .unwrap();
+        // The characteristic is on the device, but there's no third instance
+        let third = disconnected(&["--require-char", "--service-instance", "2"]);
+        let missing = device();
+        assert!(
+            connect_and_discover(&missing, &third, ConnectReason::Initial)
+                .await
+                .is_err()
+        );
+        assert!(!missing.is_connected().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn raw_sends_are_buffered_while_disconnected_too() {
+        let state = disconnected(&["--buffer-while-disconnected"]);
+        let reply = call(
+            &state,
+            post("/send/raw", serde_json::json!({ "hex": "1b5b" })),
+        )
+        .await;
+        assert_eq!(reply.status, StatusCode::ACCEPTED, "{}", reply.body);
+        assert_eq!(reply.body["status"], "buffered");
+        assert_eq!(reply.body["buffered"], 1);
+        let buffered = state.offline.as_ref().unwrap().drain();
+        assert_eq!(buffered[0].parts, [b"\x1b[".to_vec()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn smoothed_bursts_are_written_at_the_configured_rate() {
+        let device = controller(1);
+        let state = connected(&["--smooth-rate", "10"], &device);
+        for message in ["one", "two", "three"] {
+            let reply = call(
+                &state,
+                post("/send", serde_json::json!({ "message": message })),
+            )
+            .await;
+            assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        }
+        time::sleep(Duration::from_secs(1)).await;
+
+        let written: Vec<_> = device
+            .ops
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|op| match op {
+                mock_ble::Op::Write { data, at, .. } => Some((data.clone(), *at)),
+                _ => None,
+            })
+            .collect();
+        let messages: Vec<_> = written.iter().map(|(data, _)| data.as_slice()).collect();
+        assert_eq!(messages, [b"one".as_slice(), b"two", b"three"]);
+        // 10 per second: one every 100ms, however fast they were sent
+        for pair in written.windows(2) {
+            let gap = pair[1].1 - pair[0].1;
+            assert!(gap >= Duration::from_millis(100), "{:?}", gap);
+            assert!(gap < Duration::from_millis(150), "{:?}", gap);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ready_probes_fail_when_the_device_refuses_or_ignores_the_write() {
+        let device = controller(1);
+        let state = connected(&["--ready-probe-timeout-ms", "100"], &device);
+        let reply = call(&state, get("/ready")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        let reply = call(&state, get("/ready?probe=true")).await;
+        assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        assert_eq!(reply.body["probed"], true);
+
+        *device.write_error.lock().unwrap() = Some("Write rejected".to_string());
+        let reply = call(&state, get("/ready?probe=true")).await;
+        assert_eq!(
+            reply.status,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "{}",
+            reply.body
+        );
+        assert!(
+            reply.body["message"]
+                .as_str()
+                .unwrap()
+                .contains("Probe write failed"),
+            "{}",
+            reply.body
+        );
+
+        *device.write_error.lock().unwrap() = None;
+        device.unacknowledged.store(true, Ordering::Relaxed);
+        let reply = call(&state, get("/ready?probe=true")).await;
+        assert_eq!(
+            reply.status,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "{}",
+            reply.body
+        );
+        assert!(
+            reply.body["message"]
+                .as_str()
+                .unwrap()
+                .contains("timed out"),
+            "{}",
+            reply.body
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_before_write_refuses_a_stale_device() {
+        let device = controller(1);
+        let state = connected(&["--verify-before-write"], &device);
+        // Still held, but the platform says the link is gone
+        device.connected.store(false, Ordering::Relaxed);
+        let reply = call(
+            &state,
+            post("/send?sync=true", serde_json::json!({ "message": "hi" })),
+        )
+        .await;
+        assert_eq!(
+            reply.status,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "{}",
+            reply.body
+        );
+        assert_eq!(reply.body["error"], "not_connected");
+        assert_eq!(state.write_queue.depth(), 0);
+        assert_eq!(*device.ops.lock().unwrap(), []);
+        // Reported so the monitor reconnects
+        assert_eq!(*state.phase.lock().unwrap(), LinkPhase::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn writes_over_the_queue_capacity_are_rejected_and_counted() {
+        // No writer running, so the first write stays queued
+        let state = disconnected(&["--queue-capacity", "1"]);
+        let write = |data: &[u8]| {
+            let group = state.write_queue.next_group();
+            enqueue_write(
+                &state,
+                data,
+                WriteType::WithoutResponse,
+                Priority::Normal,
+                group,
+            )
+        };
+        let _queued = write(b"one").unwrap();
+        let error = write(b"two").unwrap_err();
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(state.stats.snapshot().rejected, 1);
+        assert_eq!(state.write_queue.depth(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_writes_keep_out_of_a_chunked_message() {
+        let device = controller(1);
+        let flags = [
+            "--max-inflight-writes",
+            "2",
+            "--max-ble-ops",
+            "2",
+            "--chunk-size",
+            "4",
+        ];
+        let state = connected(&flags, &device);
+        for message in ["aaaabbbbcccc", "xy"] {
+            let reply = call(
+                &state,
+                post("/send", serde_json::json!({ "message": message })),
+            )
+            .await;
+            assert_eq!(reply.status, StatusCode::OK, "{}", reply.body);
+        }
+        time::sleep(Duration::from_secs(1)).await;
+
+        let written: Vec<_> = device
+            .ops
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|op| match op {
+                mock_ble::Op::Write { data, .. } => Some(String::from_utf8(data.clone()).unwrap()),
+                _ => None,
+            })
+            .collect();
+        let chunks = ["aaaa", "bbbb", "cccc"];
+        assert!(
+            written == [&chunks[..], &["xy"]].concat()
+                || written == [&["xy"], &chunks[..]].concat(),
+            "{:?}",
+            written
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_to_a_missing_characteristic_are_404_and_keep_the_link() {
+        // Before, these failed as a 500 ble_error and the device was dropped
+        let bare =
+            PlatformPeripheral::new(addr(2), [service(CONTROLLER_SERVICE_ID.as_u128(), vec![])]);
+        let state = connected(&[], &bare);
+        let requests = [
+            post("/send?sync=true", serde_json::json!({ "message": "hi" })),
+            post("/send/raw?sync=true", serde_json::json!({ "hex": "00" })),
+        ];
+        for request in requests {
+            let reply = call(&state, request).await;
+            assert_eq!(reply.status, StatusCode::NOT_FOUND, "{}", reply.body);
+            assert_eq!(reply.body["error"], "characteristic_not_found");
+        }
+        // Also when the writer itself finds the characteristic gone
+        let error = write_now(&state, b"hi", WriteType::WithResponse)
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), "characteristic_not_found");
+
+        assert_eq!(*bare.ops.lock().unwrap(), []);
+        assert!(bare.is_connected().await.unwrap());
+        assert!(state.peripheral.lock().await.is_some());
+    }
 }
@@ -0,0 +1,285 @@
+// In-memory stand-ins for btleplug's platform Manager, Adapter and
+// Peripheral, swapped in for tests so the BLE paths run without a radio.
+// Methods mirror the btleplug trait methods main.rs calls.
+
+use btleplug::api::{
+    BDAddr, CentralEvent, CentralState, Characteristic, PeripheralProperties, ScanFilter, Service,
+    ValueNotification, WriteType,
+};
+use btleplug::{Error, Result};
+use futures::Stream;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+pub struct Manager;
+
+impl Manager {
+    pub async fn new() -> Result<Manager> {
+        Ok(Manager)
+    }
+
+    pub async fn adapters(&self) -> Result<Vec<Adapter>> {
+        Ok(vec![Adapter::default()])
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Adapter(Arc<Radio>);
+
+#[derive(Debug, Default)]
+pub struct Radio {
+    pub peripherals: Mutex<Vec<Peripheral>>,
+    /// Listings that come back empty before the peripherals show up
+    pub empty_listings: AtomicUsize,
+    /// Listings that fail, before any empty ones
+    pub failed_listings: AtomicUsize,
+    pub stop_scan_fails: AtomicBool,
+    /// Reported by adapter_state in turn; the last one sticks. PoweredOn
+    /// when empty.
+    pub states: Mutex<VecDeque<CentralState>>,
+    pub scans_started: AtomicUsize,
+    pub scans_stopped: AtomicUsize,
+    pub event_streams: AtomicUsize,
+}
+
+impl Deref for Adapter {
+    type Target = Radio;
+
+    fn deref(&self) -> &Radio {
+        &self.0
+    }
+}
+
+impl Adapter {
+    pub fn with(peripherals: Vec<Peripheral>) -> Adapter {
+        let adapter = Adapter::default();
+        *adapter.peripherals.lock().unwrap() = peripherals;
+        adapter
+    }
+
+    pub async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+        self.event_streams.fetch_add(1, Ordering::Relaxed);
+        Ok(Box::pin(futures::stream::empty()))
+    }
+
+    pub async fn start_scan(&self, _filter: ScanFilter) -> Result<()> {
+        self.scans_started.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub async fn stop_scan(&self) -> Result<()> {
+        self.scans_stopped.fetch_add(1, Ordering::Relaxed);
+        if self.stop_scan_fails.load(Ordering::Relaxed) {
+            return Err(Error::Other("No discovery started".into()));
+        }
+        Ok(())
+    }
+
+    pub async fn peripherals(&self) -> Result<Vec<Peripheral>> {
+        let take = |count: &AtomicUsize| {
+            count
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+        };
+        if take(&self.failed_listings) {
+            return Err(Error::Other("Adapter busy".into()));
+        }
+        if take(&self.empty_listings) {
+            return Ok(Vec::new());
+        }
+        Ok(self.peripherals.lock().unwrap().clone())
+    }
+
+    pub async fn adapter_info(&self) -> Result<String> {
+        Ok("mock".to_string())
+    }
+
+    pub async fn adapter_state(&self) -> Result<CentralState> {
+        let mut states = self.states.lock().unwrap();
+        let state = match states.len() {
+            0 => CentralState::PoweredOn,
+            1 => states[0].clone(),
+            _ => states.pop_front().unwrap(),
+        };
+        Ok(state)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Peripheral(Arc<Device>);
+
+#[derive(Debug)]
+pub struct Device {
+    pub address: BDAddr,
+    pub properties: Mutex<PeripheralProperties>,
+    pub services: Mutex<BTreeSet<Service>>,
+    pub connected: AtomicBool,
+    /// Values returned by read, by characteristic
+    pub values: Mutex<HashMap<Uuid, Vec<u8>>>,
+    /// Writes fail with this message
+    pub write_error: Mutex<Option<String>>,
+    /// Writes with response never complete, as if the device stopped
+    /// acknowledging them
+    pub unacknowledged: AtomicBool,
+    pub ops: Mutex<Vec<Op>>,
+    notifications: broadcast::Sender<ValueNotification>,
+}
+
+/// What was done to the device, in order
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Connect,
+    Disconnect,
+    Write {
+        uuid: Uuid,
+        data: Vec<u8>,
+        write_type: WriteType,
+        at: Instant,
+    },
+    Subscribe(Uuid),
+    Unsubscribe(Uuid),
+}
+
+impl Deref for Peripheral {
+    type Target = Device;
+
+    fn deref(&self) -> &Device {
+        &self.0
+    }
+}
+
+impl Peripheral {
+    /// A disconnected device exposing `services`, all of which it advertises
+    pub fn new(address: BDAddr, services: impl IntoIterator<Item = Service>) -> Peripheral {
+        let services: BTreeSet<Service> = services.into_iter().collect();
+        let properties = PeripheralProperties {
+            address,
+            services: services.iter().map(|s| s.uuid).collect(),
+            ..Default::default()
+        };
+        Peripheral(Arc::new(Device {
+            address,
+            properties: Mutex::new(properties),
+            services: Mutex::new(services),
+            connected: AtomicBool::new(false),
+            values: Mutex::new(HashMap::new()),
+            write_error: Mutex::new(None),
+            unacknowledged: AtomicBool::new(false),
+            ops: Mutex::new(Vec::new()),
+            notifications: broadcast::channel(16).0,
+        }))
+    }
+
+    fn record(&self, op: Op) {
+        self.ops.lock().unwrap().push(op);
+    }
+
+    pub fn address(&self) -> BDAddr {
+        self.0.address
+    }
+
+    pub async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+        Ok(Some(self.0.properties.lock().unwrap().clone()))
+    }
+
+    pub fn services(&self) -> BTreeSet<Service> {
+        self.0.services.lock().unwrap().clone()
+    }
+
+    pub fn characteristics(&self) -> BTreeSet<Characteristic> {
+        self.services()
+            .into_iter()
+            .flat_map(|s| s.characteristics)
+            .collect()
+    }
+
+    pub async fn is_connected(&self) -> Result<bool> {
+        Ok(self.connected.load(Ordering::Relaxed))
+    }
+
+    pub async fn connect(&self) -> Result<()> {
+        self.record(Op::Connect);
+        self.connected.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub async fn disconnect(&self) -> Result<()> {
+        self.record(Op::Disconnect);
+        self.connected.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub async fn discover_services(&self) -> Result<()> {
+        Ok(())
+    }
+
+    pub async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(Error::NotConnected);
+        }
+        if let Some(message) = self.write_error.lock().unwrap().clone() {
+            return Err(Error::Other(message.into()));
+        }
+        if write_type == WriteType::WithResponse && self.unacknowledged.load(Ordering::Relaxed) {
+            std::future::pending::<()>().await;
+        }
+        self.record(Op::Write {
+            uuid: characteristic.uuid,
+            data: data.to_vec(),
+            write_type,
+            at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    pub async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        if !self.connected.load(Ordering::Relaxed) {
+            return Err(Error::NotConnected);
+        }
+        self.values
+            .lock()
+            .unwrap()
+            .get(&characteristic.uuid)
+            .cloned()
+            .ok_or_else(|| Error::NotSupported("Characteristic is not readable".to_string()))
+    }
+
+    pub async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.record(Op::Subscribe(characteristic.uuid));
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.record(Op::Unsubscribe(characteristic.uuid));
+        Ok(())
+    }
+
+    pub async fn notifications(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+        let receiver = self.0.notifications.subscribe();
+        Ok(Box::pin(futures::stream::unfold(
+            receiver,
+            |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(notification) => return Some((notification, receiver)),
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )))
+    }
+}
@@ -0,0 +1,47 @@
+// Bounded buffer of notifications from --notify-uuid, drained by GET /events
+
+use crate::bounded::BoundedBuffer;
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Notification {
+    pub uuid: Uuid,
+    pub value: String,
+    pub hex: String,
+    /// Unix time received, in milliseconds
+    pub received_at: u64,
+}
+
+impl Notification {
+    pub fn new(uuid: Uuid, value: &[u8]) -> Self {
+        Notification {
+            uuid,
+            value: String::from_utf8_lossy(value).into_owned(),
+            hex: crate::hex_string(value),
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+        }
+    }
+}
+
+/// Keeps the newest notifications; older ones are dropped.
+pub type NotificationBuffer = BoundedBuffer<Notification>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_kept_as_text_and_hex() {
+        let uuid = Uuid::from_u128(1);
+        let notification = Notification::new(uuid, &[b'o', b'k', 0xff]);
+        assert_eq!(notification.uuid, uuid);
+        assert_eq!(notification.value, "ok\u{fffd}");
+        assert_eq!(notification.hex, "6f6bff");
+        assert!(notification.received_at > 0);
+    }
+}
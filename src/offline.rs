@@ -0,0 +1,14 @@
+// Messages accepted while the link is down (see --buffer-while-disconnected),
+// queued for writing in order once a device is connected again
+
+use crate::bounded::BoundedBuffer;
+use btleplug::api::WriteType;
+
+pub struct BufferedWrite {
+    /// One write per part, as /send would have queued them
+    pub parts: Vec<Vec<u8>>,
+    pub write_type: WriteType,
+}
+
+/// Keeps the newest messages; older ones are dropped.
+pub type OfflineBuffer = BoundedBuffer<BufferedWrite>;
@@ -0,0 +1,246 @@
+// Bounded queue of pending characteristic writes, drained by the writer task.
+// Higher priorities go first; within a priority writes stay in FIFO order.
+
+use crate::error::ApiError;
+use btleplug::api::WriteType;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+
+/// Outcome of a write: whether it was echoed (see --verify-notify-uuid), or
+/// the error to report.
+pub type WriteResult = Result<Option<bool>, ApiError>;
+
+/// Set per request with the `X-Priority` header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// Parse an `X-Priority` value; anything unrecognised is normal.
+    pub fn from_header(value: Option<&str>) -> Self {
+        match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+            Some("high") => Priority::High,
+            Some("low") => Priority::Low,
+            _ => Priority::Normal,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+pub struct WriteJob {
+    pub data: Vec<u8>,
+    pub write_type: WriteType,
+    pub priority: Priority,
+    /// Shared by the parts of one message (e.g. the lines of a --split-on
+    /// message), so --coalesce supersedes whole messages only.
+    pub group: u64,
+    pub enqueued_at: Instant,
+    pub done: oneshot::Sender<WriteResult>,
+}
+
+impl WriteJob {
+    /// Whether the job has waited longer than `ttl` by `now`.
+    pub fn is_expired(&self, ttl: Option<Duration>, now: Instant) -> bool {
+        ttl.is_some_and(|ttl| now.duration_since(self.enqueued_at) > ttl)
+    }
+}
+
+pub struct WriteQueue {
+    capacity: usize,
+    inner: Mutex<Inner>,
+    ready: Notify,
+    groups: AtomicU64,
+}
+
+struct Inner {
+    // One FIFO per priority, highest first
+    jobs: [VecDeque<WriteJob>; 3],
+    high_water: usize,
+}
+
+impl WriteQueue {
+    pub fn new(capacity: usize) -> Self {
+        WriteQueue {
+            capacity,
+            inner: Mutex::new(Inner {
+                jobs: Default::default(),
+                high_water: 0,
+            }),
+            ready: Notify::new(),
+            groups: AtomicU64::new(0),
+        }
+    }
+
+    /// A group id for the next message, newer than every one before it.
+    pub fn next_group(&self) -> u64 {
+        self.groups.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Queue a job, or hand it back if the queue is at capacity.
+    pub fn push(&self, job: WriteJob) -> Result<(), WriteJob> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.len() >= self.capacity {
+            return Err(job);
+        }
+        inner.jobs[job.priority.index()].push_back(job);
+        inner.high_water = inner.high_water.max(inner.len());
+        drop(inner);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Wait for the oldest job of the highest priority waiting.
+    pub async fn pop(&self) -> WriteJob {
+        loop {
+            let job = self
+                .inner
+                .lock()
+                .unwrap()
+                .jobs
+                .iter_mut()
+                .find_map(VecDeque::pop_front);
+            if let Some(job) = job {
+                return job;
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// Group of the newest message queued, if any.
+    pub fn newest_group(&self) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        inner.jobs.iter().flatten().map(|job| job.group).max()
+    }
+
+    /// Remove every queued job of a group older than `group`, oldest first
+    /// regardless of priority.
+    pub fn take_before(&self, group: u64) -> Vec<WriteJob> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut taken = Vec::new();
+        for queue in &mut inner.jobs {
+            let (older, kept): (VecDeque<_>, _) = std::mem::take(queue)
+                .into_iter()
+                .partition(|job| job.group < group);
+            *queue = kept;
+            taken.extend(older);
+        }
+        taken.sort_by_key(|job| job.enqueued_at);
+        taken
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Jobs waiting right now.
+    pub fn depth(&self) -> usize {
+        self.inner.lock().unwrap().len()
+    }
+
+    /// Most jobs ever waiting at once.
+    pub fn high_water(&self) -> usize {
+        self.inner.lock().unwrap().high_water
+    }
+}
+
+impl Inner {
+    fn len(&self) -> usize {
+        self.jobs.iter().map(VecDeque::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(data: &[u8], priority: Priority, group: u64) -> WriteJob {
+        WriteJob {
+            data: data.to_vec(),
+            write_type: WriteType::WithoutResponse,
+            priority,
+            group,
+            enqueued_at: Instant::now(),
+            done: oneshot::channel().0,
+        }
+    }
+
+    #[test]
+    fn push_hands_the_job_back_at_capacity() {
+        let queue = WriteQueue::new(2);
+        assert!(queue.push(job(b"a", Priority::Normal, 0)).is_ok());
+        assert!(queue.push(job(b"b", Priority::Normal, 1)).is_ok());
+        let rejected = queue.push(job(b"c", Priority::Normal, 2)).unwrap_err();
+        assert_eq!(rejected.data, b"c");
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.high_water(), 2);
+    }
+
+    #[test]
+    fn priority_header_values() {
+        assert_eq!(Priority::from_header(Some("high")), Priority::High);
+        assert_eq!(Priority::from_header(Some(" LOW ")), Priority::Low);
+        assert_eq!(Priority::from_header(Some("urgent")), Priority::Normal);
+        assert_eq!(Priority::from_header(None), Priority::Normal);
+    }
+
+    #[tokio::test]
+    async fn higher_priority_goes_first_and_each_stays_in_order() {
+        let queue = WriteQueue::new(8);
+        for (data, priority) in [
+            (b"n1", Priority::Normal),
+            (b"l1", Priority::Low),
+            (b"n2", Priority::Normal),
+            (b"h1", Priority::High),
+        ] {
+            queue.push(job(data, priority, 0)).ok().unwrap();
+        }
+        let mut order = Vec::new();
+        for _ in 0..4 {
+            order.push(queue.pop().await.data);
+        }
+        assert_eq!(order, [b"h1", b"n1", b"n2", b"l1"]);
+    }
+
+    #[test]
+    fn jobs_expire_only_past_the_ttl() {
+        let queued = job(b"a", Priority::Normal, 0);
+        let ttl = Duration::from_millis(100);
+        assert!(!queued.is_expired(None, queued.enqueued_at + Duration::from_secs(60)));
+        assert!(!queued.is_expired(Some(ttl), queued.enqueued_at + ttl));
+        assert!(queued.is_expired(Some(ttl), queued.enqueued_at + Duration::from_millis(101)));
+    }
+
+    #[test]
+    fn take_before_keeps_every_part_of_the_newest_message() {
+        let queue = WriteQueue::new(8);
+        let (older, newest) = (queue.next_group(), queue.next_group());
+        assert!(newest > older);
+        for (data, group) in [
+            (b"o1", older),
+            (b"o2", older),
+            (b"n1", newest),
+            (b"n2", newest),
+        ] {
+            queue.push(job(data, Priority::Normal, group)).ok().unwrap();
+        }
+        assert_eq!(queue.newest_group(), Some(newest));
+        let taken: Vec<_> = queue
+            .take_before(newest)
+            .into_iter()
+            .map(|j| j.data)
+            .collect();
+        assert_eq!(taken, [b"o1", b"o2"]);
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.newest_group(), Some(newest));
+    }
+}
@@ -0,0 +1,76 @@
+// Token bucket behind --rate-limit, shared by every client so a single busy
+// one can't flood the device by opening more connections
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct RateLimiter {
+    per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Allow `per_sec` requests a second, with bursts of up to the same
+    /// number after a quiet spell.
+    pub fn new(per_sec: u32, now: Instant) -> Self {
+        RateLimiter {
+            per_sec: per_sec.into(),
+            bucket: Mutex::new(Bucket {
+                tokens: per_sec.into(),
+                refilled_at: now,
+            }),
+        }
+    }
+
+    /// Take a token, or say how long until one is available.
+    pub fn acquire(&self, now: Instant) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_sec).min(self.per_sec);
+        bucket.refilled_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_sec,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_a_burst_then_says_when_to_retry() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(2, start);
+        assert_eq!(limiter.acquire(start), Ok(()));
+        assert_eq!(limiter.acquire(start), Ok(()));
+        assert_eq!(limiter.acquire(start), Err(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn refills_over_time_up_to_the_burst() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(2, start);
+        limiter.acquire(start).unwrap();
+        limiter.acquire(start).unwrap();
+        let later = start + Duration::from_millis(500);
+        assert_eq!(limiter.acquire(later), Ok(()));
+        assert!(limiter.acquire(later).is_err());
+
+        // A long quiet spell still only allows a burst of two
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(limiter.acquire(much_later), Ok(()));
+        assert_eq!(limiter.acquire(much_later), Ok(()));
+        assert!(limiter.acquire(much_later).is_err());
+    }
+}
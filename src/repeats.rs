@@ -0,0 +1,110 @@
+// Tracking of the last message written, to skip repeats (--collapse-repeats,
+// --status-on-change) and refresh it when idle (--status-keepalive-secs)
+
+use std::time::{Duration, Instant};
+
+pub struct RepeatFilter {
+    // A repeat is written anyway once the last write is this old, so the
+    // display is still refreshed now and then
+    refresh: Duration,
+    last: Option<(Vec<u8>, Instant)>,
+}
+
+impl RepeatFilter {
+    pub fn new(refresh: Duration) -> Self {
+        RepeatFilter {
+            refresh,
+            last: None,
+        }
+    }
+
+    /// Whether `data` at `now` repeats the last written message within the
+    /// refresh interval. Nothing is recorded; call `record` once the write
+    /// has been accepted.
+    pub fn is_repeat(&self, data: &[u8], now: Instant) -> bool {
+        match &self.last {
+            Some((last, at)) => last == data && now.duration_since(*at) < self.refresh,
+            None => false,
+        }
+    }
+
+    /// Record `data` as written at `now`.
+    pub fn record(&mut self, data: &[u8], now: Instant) {
+        self.last = Some((data.to_vec(), now));
+    }
+
+    /// The last message, if its write is at least the refresh interval old.
+    /// It counts as rewritten at `now`.
+    pub fn due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let (last, at) = self.last.as_mut()?;
+        if now.duration_since(*at) < self.refresh {
+            return None;
+        }
+        *at = now;
+        Some(last.clone())
+    }
+
+    /// Drop the last message, e.g. because its write failed or the device
+    /// reconnected and needs redrawing.
+    pub fn forget(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFRESH: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn repeats_are_suppressed_until_the_refresh_boundary() {
+        let start = Instant::now();
+        let mut filter = RepeatFilter::new(REFRESH);
+        assert!(!filter.is_repeat(b"hi", start));
+        filter.record(b"hi", start);
+        assert!(filter.is_repeat(b"hi", start + Duration::from_secs(59)));
+        assert!(!filter.is_repeat(b"hi", start + REFRESH));
+    }
+
+    #[test]
+    fn a_check_alone_records_nothing() {
+        let start = Instant::now();
+        let mut filter = RepeatFilter::new(REFRESH);
+        assert!(!filter.is_repeat(b"hi", start));
+        assert!(!filter.is_repeat(b"hi", start));
+        filter.record(b"hi", start);
+        assert!(filter.is_repeat(b"hi", start));
+    }
+
+    #[test]
+    fn a_different_message_is_written_and_becomes_the_last() {
+        let start = Instant::now();
+        let mut filter = RepeatFilter::new(REFRESH);
+        filter.record(b"hi", start);
+        assert!(!filter.is_repeat(b"bye", start));
+        filter.record(b"bye", start);
+        assert!(!filter.is_repeat(b"hi", start));
+    }
+
+    #[test]
+    fn forget_lets_the_same_message_through() {
+        let start = Instant::now();
+        let mut filter = RepeatFilter::new(REFRESH);
+        filter.record(b"hi", start);
+        assert!(filter.is_repeat(b"hi", start));
+        filter.forget();
+        assert!(!filter.is_repeat(b"hi", start));
+    }
+
+    #[test]
+    fn due_returns_the_last_message_once_per_interval() {
+        let start = Instant::now();
+        let mut filter = RepeatFilter::new(REFRESH);
+        assert_eq!(filter.due(start + REFRESH), None);
+        filter.record(b"[busy]", start);
+        assert_eq!(filter.due(start + Duration::from_secs(30)), None);
+        assert_eq!(filter.due(start + REFRESH), Some(b"[busy]".to_vec()));
+        assert_eq!(filter.due(start + REFRESH), None);
+    }
+}
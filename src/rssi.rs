@@ -0,0 +1,106 @@
+// Rolling window of RSSI samples taken by the monitor task while connected
+
+use crate::bounded::BoundedBuffer;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Least-squares slope (dBm per sample) beyond which the signal counts as
+// improving or degrading
+const TREND_THRESHOLD: f64 = 0.1;
+
+#[derive(Clone, Copy, Serialize)]
+pub struct RssiSample {
+    /// Milliseconds since the Unix epoch
+    pub timestamp: u64,
+    pub rssi: i16,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trend {
+    Improving,
+    Degrading,
+    Stable,
+    Unknown,
+}
+
+pub struct RssiHistory {
+    samples: BoundedBuffer<RssiSample>,
+}
+
+impl RssiHistory {
+    pub fn new(capacity: usize) -> Self {
+        RssiHistory {
+            samples: BoundedBuffer::new(capacity),
+        }
+    }
+
+    pub fn push(&self, rssi: i16) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.samples.push(RssiSample { timestamp, rssi });
+    }
+
+    pub fn samples(&self) -> Vec<RssiSample> {
+        self.samples.items()
+    }
+
+    pub fn trend(&self) -> Trend {
+        let values: Vec<i16> = self.samples().iter().map(|s| s.rssi).collect();
+        trend(&values)
+    }
+}
+
+/// Classify a series of RSSI values (oldest first) by its linear trend.
+pub fn trend(values: &[i16]) -> Trend {
+    if values.len() < 2 {
+        return Trend::Unknown;
+    }
+    let n = values.len() as f64;
+    let mean_x = (n - 1.0) / 2.0;
+    let mean_y = values.iter().map(|&v| f64::from(v)).sum::<f64>() / n;
+    let (mut num, mut den) = (0.0, 0.0);
+    for (i, &v) in values.iter().enumerate() {
+        let dx = i as f64 - mean_x;
+        num += dx * (f64::from(v) - mean_y);
+        den += dx * dx;
+    }
+    let slope = num / den;
+    if slope > TREND_THRESHOLD {
+        Trend::Improving
+    } else if slope < -TREND_THRESHOLD {
+        Trend::Degrading
+    } else {
+        Trend::Stable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trend_follows_the_slope() {
+        assert_eq!(trend(&[-80, -75, -70, -66]), Trend::Improving);
+        assert_eq!(trend(&[-50, -58, -63, -70]), Trend::Degrading);
+        assert_eq!(trend(&[-60, -61, -60, -61, -60]), Trend::Stable);
+    }
+
+    #[test]
+    fn trend_needs_two_samples() {
+        assert_eq!(trend(&[]), Trend::Unknown);
+        assert_eq!(trend(&[-60]), Trend::Unknown);
+    }
+
+    #[test]
+    fn history_keeps_the_newest_samples() {
+        let history = RssiHistory::new(3);
+        for rssi in [-90, -80, -70, -60] {
+            history.push(rssi);
+        }
+        let kept: Vec<i16> = history.samples().iter().map(|s| s.rssi).collect();
+        assert_eq!(kept, [-80, -70, -60]);
+        assert_eq!(history.trend(), Trend::Improving);
+    }
+}
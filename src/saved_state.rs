@@ -0,0 +1,77 @@
+// What --state-file remembers across restarts: the last device connected to,
+// so startup can go straight to it instead of waiting out a full scan
+
+use anyhow::Context;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct SavedState {
+    /// e.g. "AA:BB:CC:DD:EE:FF"
+    pub last_address: Option<String>,
+    pub last_name: Option<String>,
+}
+
+impl SavedState {
+    /// Read `path`; a missing file is a clean slate, and an unreadable one is
+    /// reported and ignored rather than blocking startup.
+    pub fn load(path: &Path) -> SavedState {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return SavedState::default(),
+            Err(e) => {
+                warn!("Ignoring saved state {}: {}", path.display(), e);
+                return SavedState::default();
+            }
+        };
+        serde_json::from_str(&text).unwrap_or_else(|e| {
+            warn!("Ignoring malformed saved state {}: {}", path.display(), e);
+            SavedState::default()
+        })
+    }
+
+    /// Write to a temporary file first so a crash mid-write can't leave a
+    /// truncated state file behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("vibekeys-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn save_then_load_round_trips() {
+        let path = temp_path("state.json");
+        let saved = SavedState {
+            last_address: Some("AA:BB:CC:DD:EE:FF".to_string()),
+            last_name: Some("Keys".to_string()),
+        };
+        saved.save(&path).unwrap();
+        let loaded = SavedState::load(&path);
+        std::fs::remove_file(&path).ok();
+        assert_eq!(loaded.last_address.as_deref(), Some("AA:BB:CC:DD:EE:FF"));
+        assert_eq!(loaded.last_name.as_deref(), Some("Keys"));
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn missing_or_malformed_files_are_a_clean_slate() {
+        let missing = SavedState::load(&temp_path("missing.json"));
+        assert!(missing.last_address.is_none());
+        let path = temp_path("malformed.json");
+        std::fs::write(&path, "{not json").unwrap();
+        let malformed = SavedState::load(&path);
+        std::fs::remove_file(&path).ok();
+        assert!(malformed.last_address.is_none());
+    }
+}
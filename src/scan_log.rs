@@ -0,0 +1,79 @@
+// Suppress repeated info-level scan logging for devices that haven't changed
+
+use btleplug::api::BDAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// RSSI change (dBm) large enough to log a device again inside the window
+const RSSI_CHANGE_DBM: i16 = 10;
+
+struct Seen {
+    name: String,
+    rssi: i16,
+    logged_at: Instant,
+}
+
+pub struct ScanLogThrottle {
+    window: Duration,
+    seen: HashMap<BDAddr, Seen>,
+}
+
+impl ScanLogThrottle {
+    pub fn new(window: Duration) -> Self {
+        ScanLogThrottle {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Whether a scan result should be logged at info level: true for a new
+    /// device, a changed name, a significant RSSI change, or once the window
+    /// since it was last logged has passed.
+    pub fn should_log(&mut self, addr: BDAddr, name: &str, rssi: i16, now: Instant) -> bool {
+        if let Some(seen) = self.seen.get(&addr) {
+            let unchanged = seen.name == name && (seen.rssi - rssi).abs() < RSSI_CHANGE_DBM;
+            if unchanged && now.duration_since(seen.logged_at) < self.window {
+                return false;
+            }
+        }
+        self.seen.insert(
+            addr,
+            Seen {
+                name: name.to_string(),
+                rssi,
+                logged_at: now,
+            },
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8) -> BDAddr {
+        BDAddr::from([0xaa, 0xbb, 0xcc, 0xdd, 0xee, last])
+    }
+
+    #[test]
+    fn unchanged_device_is_logged_once_per_window() {
+        let start = Instant::now();
+        let mut throttle = ScanLogThrottle::new(Duration::from_secs(30));
+        assert!(throttle.should_log(addr(1), "Keys", -60, start));
+        let later = start + Duration::from_secs(10);
+        assert!(!throttle.should_log(addr(1), "Keys", -65, later));
+        let after_window = start + Duration::from_secs(31);
+        assert!(throttle.should_log(addr(1), "Keys", -60, after_window));
+    }
+
+    #[test]
+    fn changes_are_logged_inside_the_window() {
+        let start = Instant::now();
+        let mut throttle = ScanLogThrottle::new(Duration::from_secs(30));
+        assert!(throttle.should_log(addr(1), "Keys", -60, start));
+        assert!(throttle.should_log(addr(1), "Keys v2", -60, start));
+        assert!(throttle.should_log(addr(1), "Keys v2", -70, start));
+        assert!(throttle.should_log(addr(2), "Keys v2", -70, start));
+    }
+}
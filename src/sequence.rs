@@ -0,0 +1,74 @@
+// Sequence-number framing for --sequence-bytes: each write is prefixed with
+// an incrementing counter, and the device confirms it by echoing the counter
+// back on --verify-notify-uuid
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub struct Sequence {
+    width: usize,
+    next: AtomicU32,
+}
+
+impl Sequence {
+    /// `width` is the counter size in bytes, 1 to 4; the counter wraps
+    /// within it.
+    pub fn new(width: u8) -> Self {
+        Sequence {
+            width: width.clamp(1, 4).into(),
+            next: AtomicU32::new(0),
+        }
+    }
+
+    /// Take the next counter value, big-endian in `width` bytes.
+    pub fn next(&self) -> Vec<u8> {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        n.to_be_bytes()[4 - self.width..].to_vec()
+    }
+
+    /// Prefix `data` with the next counter value, returning both.
+    pub fn frame(&self, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let seq = self.next();
+        let mut framed = Vec::with_capacity(seq.len() + data.len());
+        framed.extend_from_slice(&seq);
+        framed.extend_from_slice(data);
+        (seq, framed)
+    }
+}
+
+/// Whether a notification confirms the write numbered `seq`.
+pub fn confirms(seq: &[u8], notification: &[u8]) -> bool {
+    notification.starts_with(seq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_increments_and_wraps_within_its_width() {
+        let sequence = Sequence::new(1);
+        assert_eq!(sequence.next(), [0]);
+        assert_eq!(sequence.next(), [1]);
+        for _ in 2..256 {
+            sequence.next();
+        }
+        assert_eq!(sequence.next(), [0]);
+    }
+
+    #[test]
+    fn frame_prefixes_the_big_endian_counter() {
+        let sequence = Sequence::new(2);
+        sequence.next();
+        let (seq, framed) = sequence.frame(b"hi");
+        assert_eq!(seq, [0, 1]);
+        assert_eq!(framed, [0, 1, b'h', b'i']);
+    }
+
+    #[test]
+    fn confirms_matches_the_echoed_counter_only() {
+        assert!(confirms(&[0, 7], &[0, 7]));
+        assert!(confirms(&[0, 7], &[0, 7, b'o', b'k']));
+        assert!(!confirms(&[0, 7], &[0, 6]));
+        assert!(!confirms(&[0, 7], &[0]));
+    }
+}
@@ -0,0 +1,109 @@
+// Client sessions from POST /session, used to attribute writes when several
+// clients share the server. Sessions don't change how writes are made.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Default)]
+pub struct Sessions {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    sessions: BTreeMap<String, Session>,
+    last_writer: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Session {
+    /// Unix time the session was created, in seconds
+    pub created_at: u64,
+    pub writes: u64,
+}
+
+#[derive(Serialize)]
+pub struct SessionReport {
+    /// Session of the most recent write; null if it carried no session
+    pub last_writer: Option<String>,
+    pub sessions: BTreeMap<String, Session>,
+}
+
+impl Sessions {
+    /// Create a session and return its token.
+    pub fn create(&self) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.inner.lock().unwrap().sessions.insert(
+            token.clone(),
+            Session {
+                created_at,
+                writes: 0,
+            },
+        );
+        token
+    }
+
+    /// Attribute a write to `token` (None for a write without a session).
+    /// Returns false for a token that was never issued, which is then not
+    /// recorded.
+    pub fn record_write(&self, token: Option<&str>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(token) = token else {
+            inner.last_writer = None;
+            return true;
+        };
+        let Some(session) = inner.sessions.get_mut(token) else {
+            return false;
+        };
+        session.writes += 1;
+        inner.last_writer = Some(token.to_string());
+        true
+    }
+
+    pub fn report(&self) -> SessionReport {
+        let inner = self.inner.lock().unwrap();
+        SessionReport {
+            last_writer: inner.last_writer.clone(),
+            sessions: inner.sessions.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_writer_is_the_session_of_the_latest_write() {
+        let sessions = Sessions::default();
+        let a = sessions.create();
+        let b = sessions.create();
+        assert!(sessions.record_write(Some(&a)));
+        assert!(sessions.record_write(Some(&b)));
+        assert!(sessions.record_write(Some(&a)));
+        let report = sessions.report();
+        assert_eq!(report.last_writer.as_deref(), Some(a.as_str()));
+        assert_eq!(report.sessions[&a].writes, 2);
+        assert_eq!(report.sessions[&b].writes, 1);
+
+        assert!(sessions.record_write(None));
+        assert_eq!(sessions.report().last_writer, None);
+    }
+
+    #[test]
+    fn unknown_tokens_are_not_recorded() {
+        let sessions = Sessions::default();
+        let a = sessions.create();
+        sessions.record_write(Some(&a));
+        assert!(!sessions.record_write(Some("not-a-session")));
+        let report = sessions.report();
+        assert_eq!(report.last_writer.as_deref(), Some(a.as_str()));
+        assert_eq!(report.sessions.len(), 1);
+    }
+}
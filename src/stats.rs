@@ -0,0 +1,77 @@
+// Counters reported by GET /stats
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Default)]
+pub struct Stats {
+    writes_ok: AtomicU64,
+    writes_failed: AtomicU64,
+    expired: AtomicU64,
+    coalesced: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct StatsSnapshot {
+    pub writes_ok: u64,
+    pub writes_failed: u64,
+    pub expired: u64,
+    pub coalesced: u64,
+    /// Writes refused because the queue was full
+    pub rejected: u64,
+}
+
+impl Stats {
+    pub fn write_ok(&self) {
+        self.writes_ok.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn write_failed(&self) {
+        self.writes_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn expired(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            writes_ok: self.writes_ok.load(Ordering::Relaxed),
+            writes_failed: self.writes_failed.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_outcome_has_its_own_counter() {
+        let stats = Stats::default();
+        stats.write_ok();
+        stats.write_ok();
+        stats.write_failed();
+        stats.expired();
+        stats.rejected();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.writes_ok, 2);
+        assert_eq!(snapshot.writes_failed, 1);
+        assert_eq!(snapshot.expired, 1);
+        assert_eq!(snapshot.coalesced, 0);
+        assert_eq!(snapshot.rejected, 1);
+    }
+}
@@ -0,0 +1,116 @@
+// Active streaming clients (e.g. GET /events), listed by GET /subscribers
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+#[derive(Default)]
+pub struct Subscribers {
+    next_id: Mutex<u64>,
+    active: Mutex<BTreeMap<u64, Subscriber>>,
+}
+
+struct Subscriber {
+    stream: &'static str,
+    connected_at: u64,
+    since: Instant,
+}
+
+#[derive(Serialize)]
+pub struct SubscriberInfo {
+    pub id: u64,
+    /// Which feed the client is reading
+    pub stream: &'static str,
+    /// Unix time the client connected, in seconds
+    pub connected_at: u64,
+    pub connected_secs: u64,
+    /// Characteristics whose notifications the feed carries
+    pub characteristics: Vec<Uuid>,
+}
+
+impl Subscribers {
+    /// Record a new client on `stream`; it is removed when the guard drops.
+    pub fn register(self: &Arc<Self>, stream: &'static str) -> SubscriberGuard {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        let connected_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.active.lock().unwrap().insert(
+            id,
+            Subscriber {
+                stream,
+                connected_at,
+                since: Instant::now(),
+            },
+        );
+        SubscriberGuard {
+            id,
+            subscribers: self.clone(),
+        }
+    }
+
+    /// Every client, each reported as receiving notifications from
+    /// `characteristics` (the feed is shared, so it is the same for all).
+    pub fn list(&self, characteristics: &[Uuid]) -> Vec<SubscriberInfo> {
+        self.active
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, s)| SubscriberInfo {
+                id,
+                stream: s.stream,
+                connected_at: s.connected_at,
+                connected_secs: s.since.elapsed().as_secs(),
+                characteristics: characteristics.to_vec(),
+            })
+            .collect()
+    }
+}
+
+/// Keeps a client listed while the stream serving it is alive.
+pub struct SubscriberGuard {
+    id: u64,
+    subscribers: Arc<Subscribers>,
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        self.subscribers.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_are_listed_while_their_guard_lives() {
+        let subscribers = Arc::new(Subscribers::default());
+        assert!(subscribers.list(&[]).is_empty());
+        let first = subscribers.register("events");
+        let second = subscribers.register("events");
+        assert_eq!(subscribers.list(&[]).len(), 2);
+        drop(first);
+        let listed = subscribers.list(&[]);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, 2);
+        drop(second);
+        assert!(subscribers.list(&[]).is_empty());
+    }
+
+    #[test]
+    fn each_client_reports_the_characteristics_given() {
+        let subscribers = Arc::new(Subscribers::default());
+        let _guard = subscribers.register("events");
+        let uuid = Uuid::from_u128(0x1234);
+        let listed = subscribers.list(&[uuid]);
+        assert_eq!(listed[0].stream, "events");
+        assert_eq!(listed[0].characteristics, [uuid]);
+    }
+}
@@ -0,0 +1,61 @@
+// Application-level link supervision (see --supervision-timeout-ms). The OS
+// can take seconds to notice a dead link; a device that stops answering our
+// own writes and reads is treated as gone sooner.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct Supervision {
+    // When the first operation since the last success was attempted
+    unanswered_since: Mutex<Option<Instant>>,
+}
+
+impl Supervision {
+    /// An operation on the device is starting.
+    pub fn attempt(&self, now: Instant) {
+        self.unanswered_since.lock().unwrap().get_or_insert(now);
+    }
+
+    /// An operation on the device succeeded, so the link is alive.
+    pub fn success(&self) {
+        *self.unanswered_since.lock().unwrap() = None;
+    }
+
+    /// Whether operations have gone unanswered for longer than `timeout`.
+    /// An idle link never expires.
+    pub fn expired(&self, timeout: Duration, now: Instant) -> bool {
+        self.unanswered_since
+            .lock()
+            .unwrap()
+            .is_some_and(|since| now.duration_since(since) > timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn unanswered_operations_expire_after_the_timeout() {
+        let start = Instant::now();
+        let supervision = Supervision::default();
+        supervision.attempt(start);
+        // A later attempt doesn't restart the clock
+        supervision.attempt(start + Duration::from_millis(400));
+        assert!(!supervision.expired(TIMEOUT, start + Duration::from_millis(500)));
+        assert!(supervision.expired(TIMEOUT, start + Duration::from_millis(501)));
+    }
+
+    #[test]
+    fn success_and_idle_never_expire() {
+        let start = Instant::now();
+        let supervision = Supervision::default();
+        assert!(!supervision.expired(TIMEOUT, start + Duration::from_secs(60)));
+        supervision.attempt(start);
+        supervision.success();
+        assert!(!supervision.expired(TIMEOUT, start + Duration::from_secs(60)));
+    }
+}
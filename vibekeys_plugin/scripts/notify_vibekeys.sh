@@ -4,25 +4,31 @@
 VIBEKEYS_APP_URL="${VIBEKEYS_APP_URL:-http://127.0.0.1:57001}"
 ACTION="$1"
 
+# Matches the app's --auth-token, when it was started with one
+AUTH=()
+if [ -n "$VIBEKEYS_AUTH_TOKEN" ]; then
+    AUTH=(-H "Authorization: Bearer $VIBEKEYS_AUTH_TOKEN")
+fi
+
 case "$ACTION" in
     working|stopped|pending)
         # Status update - send in background and exit 0
         curl -s -X POST "$VIBEKEYS_APP_URL/status" \
-          -H 'Content-Type: application/json' \
+          -H 'Content-Type: application/json' "${AUTH[@]}" \
           -d "{\"status\":\"$ACTION\"}" >/dev/null 2>&1 &
         exit 0
         ;;
     notify)
         # Notification event - test
         curl -s -X POST "$VIBEKEYS_APP_URL/send" \
-          -H 'Content-Type: application/json' \
+          -H 'Content-Type: application/json' "${AUTH[@]}" \
           -d "{\"message\":\"notify\"}" >/dev/null 2>&1 &
         exit 0
         ;;
     tool)
         # PreToolUse - send "tool use" message and return "ask" decision
         curl -s -X POST "$VIBEKEYS_APP_URL/send" \
-          -H 'Content-Type: application/json' \
+          -H 'Content-Type: application/json' "${AUTH[@]}" \
           -d "{\"message\":\"tool use\"}" >/dev/null 2>&1 &
         echo "{\"permissionDecision\":\"ask\"}"
         exit 0
@@ -30,14 +36,14 @@ case "$ACTION" in
     post)
         # PostToolUse - send "post tool" message
         curl -s -X POST "$VIBEKEYS_APP_URL/send" \
-          -H 'Content-Type: application/json' \
+          -H 'Content-Type: application/json' "${AUTH[@]}" \
           -d "{\"message\":\"post tool\"}" >/dev/null 2>&1 &
         exit 0
         ;;
     ask)
         # PreToolUse - send pending and return "ask" decision
         curl -s -X POST "$VIBEKEYS_APP_URL/status" \
-          -H 'Content-Type: application/json' \
+          -H 'Content-Type: application/json' "${AUTH[@]}" \
           -d "{\"status\":\"pending\"}" >/dev/null 2>&1 &
         echo "{\"permissionDecision\":\"ask\"}"
         exit 0
@@ -50,7 +56,7 @@ case "$ACTION" in
             exit 1
         fi
         curl -s -X POST "$VIBEKEYS_APP_URL/send" \
-          -H 'Content-Type: application/json' \
+          -H 'Content-Type: application/json' "${AUTH[@]}" \
           -d "{\"message\":\"$MESSAGE\"}" >/dev/null 2>&1 &
         exit 0
         ;;